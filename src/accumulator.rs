@@ -0,0 +1,164 @@
+//! Keeps track of what the worker is holding, to leave out the `COPYFROM`s of a tile whose value is
+//! in their hands already. The same goes for values that are worked out, like `COPYFROM 3; SUB 4`,
+//! which don't have to be worked out again while the hands still hold them, and which can be
+//! picked up from a tile instead when they were put on one.
+
+use crate::compiler::{Command, Reference};
+use crate::ir::Cfg;
+
+/// The longest expression that is remembered
+const EXPRESSION_LIMIT: usize = 4;
+
+/// A `COPYFROM` followed by `ADD`s and `SUB`s, which only change the hands. Running it gives a
+/// value, so the hands or a tile can be known to hold the same value as it.
+type Expression = Vec<Command>;
+
+/// What is known about the values at one point in the program
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Known {
+    /// The expressions with the value the hands hold. It is empty when the hands are empty or hold
+    /// a value that can't be worked out again.
+    hand: Vec<Expression>,
+    /// Tiles holding the value of an expression longer than one command
+    tiles: Vec<(u8, Expression)>,
+}
+
+/// Removes every `COPYFROM` of a tile that holds what is in the hands already, and every expression
+/// that is worked out again while its value is in the hands, no matter which way the program got
+/// there. Expressions whose value is on a tile are picked up from there instead.
+pub fn remove_redundant_loads(cfg: &mut Cfg) {
+    let starts = known_at_start(cfg);
+    for (block, start) in cfg.blocks.iter_mut().zip(starts) {
+        let mut known = start;
+        let mut commands = Vec::with_capacity(block.commands.len());
+        let mut i = 0;
+        while i < block.commands.len() {
+            let rest = &block.commands[i..];
+            let held = known.hand.iter().filter(|e| rest.starts_with(e)).max_by_key(|e| e.len());
+            //picking the value up from a tile still takes a command
+            let stored = known.tiles.iter()
+                .filter(|(_, e)| rest.starts_with(e))
+                .max_by_key(|(_, e)| e.len())
+                .filter(|(_, e)| held.is_none_or(|held| e.len() > held.len() + 1));
+            if let Some((tile, stored)) = stored {
+                let tile = Reference::Pointer(*tile);
+                i += stored.len();
+                known.hand = vec![stored.clone(), vec![Command::CopyFrom(tile.clone())]];
+                commands.push(Command::CopyFrom(tile));
+            } else if let Some(held) = held {
+                i += held.len();
+            } else {
+                known = after(&known, &rest[0]);
+                commands.push(rest[0].clone());
+                i += 1;
+            }
+        }
+        block.commands = commands;
+    }
+}
+
+/// What is known at the start of each block, which is what all of the ways into the block agree on
+fn known_at_start(cfg: &Cfg) -> Vec<Known> {
+    //`None` for the blocks that no way into has been looked at yet
+    let mut starts: Vec<Option<Known>> = vec![None; cfg.blocks.len()];
+    if let Some(first) = starts.first_mut() {
+        *first = Some(Known::default());
+    }
+    
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            let start = match &starts[id] {
+                Some(start) => start.clone(),
+                None => continue,
+            };
+            let end = block.commands.iter().fold(start, |known, command| after(&known, command));
+            for next in block.terminator.successors() {
+                let merged = match &starts[next] {
+                    Some(known) => Known {
+                        hand: known.hand.iter().filter(|e| end.hand.contains(e)).cloned().collect(),
+                        tiles: known.tiles.iter().filter(|e| end.tiles.contains(e)).cloned().collect(),
+                    },
+                    None => end.clone(),
+                };
+                if starts[next].as_ref() != Some(&merged) {
+                    starts[next] = Some(merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+    
+    starts.into_iter().map(Option::unwrap_or_default).collect()
+}
+
+/// What is known after `command`
+fn after(known: &Known, command: &Command) -> Known {
+    match command {
+        Command::CopyFrom(_) => Known {
+            hand: vec![vec![command.clone()]],
+            tiles: known.tiles.clone(),
+        },
+        Command::Add(_) | Command::Subtract(_) => Known {
+            hand: known.hand.iter()
+                .filter(|e| e.len() < EXPRESSION_LIMIT)
+                .map(|e| e.iter().chain(std::iter::once(command)).cloned().collect())
+                .collect(),
+            tiles: known.tiles.clone(),
+        },
+        //the tile that is written to gets the value in the hands, so the other tiles that hold it
+        //still do. Only the tiles reached through a pointer that might have changed are lost, and
+        //the expressions that might have used the tile.
+        Command::CopyTo(Reference::Pointer(tile)) => {
+            let hand: Vec<Expression> = known.hand.iter()
+                .filter(|e| match &e[..] {
+                    [Command::CopyFrom(from)] => *from != Reference::PointerPointer(*tile),
+                    _ => !uses(e, *tile),
+                })
+                .cloned()
+                .collect();
+            let stored = hand.iter().filter(|e| e.len() > 1).map(|e| (*tile, e.clone()));
+            Known {
+                tiles: still_held(&known.tiles, *tile).chain(stored).collect(),
+                hand: hand.iter()
+                    .cloned()
+                    .chain(std::iter::once(vec![Command::CopyFrom(Reference::Pointer(*tile))]))
+                    .collect(),
+            }
+        }
+        Command::CopyTo(_) => Known {
+            hand: known.hand.iter()
+                .filter(|e| matches!(&e[..], [Command::CopyFrom(Reference::Pointer(_))]))
+                .cloned()
+                .collect(),
+            tiles: vec![],
+        },
+        Command::Increment(Reference::Pointer(tile)) |
+        Command::Decrement(Reference::Pointer(tile)) => Known {
+            hand: vec![vec![Command::CopyFrom(Reference::Pointer(*tile))]],
+            tiles: still_held(&known.tiles, *tile).collect(),
+        },
+        Command::Increment(_) | Command::Decrement(_) => Known::default(),
+        Command::Inbox | Command::Outbox => Known { hand: vec![], tiles: known.tiles.clone() },
+        Command::Jump(_) | Command::JumpIfZero(_) | Command::JumpIfNegative(_) |
+        Command::Label(_) | Command::Comment(_) => known.clone(),
+    }
+}
+
+/// The tiles that still hold the value of their expression after `tile` changed
+fn still_held(tiles: &[(u8, Expression)], tile: u8) -> impl Iterator<Item = (u8, Expression)> + '_ {
+    tiles.iter().filter(move |(held, e)| *held != tile && !uses(e, tile)).cloned()
+}
+
+/// Whether the value of `expression` might change when `tile` does. Anything read through a
+/// pointer could be `tile`.
+fn uses(expression: &[Command], tile: u8) -> bool {
+    expression.iter().any(|command| match command {
+        Command::CopyFrom(from) | Command::Add(from) | Command::Subtract(from) => match from {
+            Reference::Pointer(from) => *from == tile,
+            _ => true,
+        },
+        _ => true,
+    })
+}
\ No newline at end of file
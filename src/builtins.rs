@@ -0,0 +1,386 @@
+//! Built-in functions for the snippets that most levels need. They are called like
+//! `output(abs(*3));` and expand into hand-written instruction sequences.
+//!
+//! - `abs(x)` gives the absolute value of `x`.
+//! - `min(a, b)` and `max(a, b)` give the smaller and the larger of `a` and `b`. With only one
+//!   scratch tile free they subtract, so `a` and `b` can't be more than 999 apart.
+//! - `sign(x)` gives -1, 0 or 1. Needs `#constant 1` and `#constant -1`.
+//! - `read_string(ptr)` reads values from the inbox into the tile `ptr` points at, bumping `ptr`
+//!   after each one, until it has stored a zero. `ptr` is left pointing at the zero.
+//! - `write_string(ptr)` sends values to the outbox starting at the tile `ptr` points at, until it
+//!   reaches a zero. The zero isn't sent, and `ptr` is left pointing at it.
+//! - `countdown(x)` sends `x`, then every number between `x` and zero, then zero to the outbox.
+//! - `comment(n)` puts the HRM comment marker `COMMENT n` into the program. `--keep-comments` turns
+//!   comments in the source into these.
+//!
+//! `ptr` has to be a tile, like `*4` or a macro for one. The functions that need somewhere to keep
+//! a value in between use one of the scratch tiles (see `#scratch`).
+
+use crate::compiler::{Command, Construct, Label, LabelGenerator, Memory, Reference};
+use crate::parser2::{AnyExpressionType, Expression, Value, Deref, SideEffects, known_constant, simplify_in_place};
+use crate::CompileError;
+
+const FUNCTIONS: &[&str] = &["abs", "sign", "countdown", "min", "max", "read_string", "write_string", "comment"];
+
+/// Looks up the built-in function called `name` and hands it its arguments.
+pub fn call(name: &str, mut arguments: Vec<AnyExpressionType>) -> Result<Box<dyn Expression>, CompileError> {
+    let invalid = || CompileError::InvalidArguments(name.to_string());
+    
+    match (name, arguments.len()) {
+        ("abs", 1) => Ok(Box::new(Abs::new(arguments.remove(0).into_expression().ok_or_else(invalid)?))),
+        ("sign", 1) => Ok(Box::new(Sign::new(arguments.remove(0).into_expression().ok_or_else(invalid)?))),
+        ("countdown", 1) => Ok(Box::new(Countdown::new(arguments.remove(0).into_expression().ok_or_else(invalid)?))),
+        ("min", 2) | ("max", 2) => {
+            let left = arguments.remove(0).into_expression().ok_or_else(invalid)?;
+            let right = arguments.remove(0).into_expression().ok_or_else(invalid)?;
+            Ok(Box::new(Extremum::new(left, right, name == "max")))
+        }
+        ("read_string", 1) => Ok(Box::new(ReadString::new(pointer_tile(arguments.remove(0)).ok_or_else(invalid)?))),
+        ("write_string", 1) => Ok(Box::new(WriteString::new(pointer_tile(arguments.remove(0)).ok_or_else(invalid)?))),
+        ("comment", 1) => match arguments.remove(0).value().map(|e| e.value()) {
+            Some(Ok(Reference::Number(number))) => Ok(Box::new(Comment::new(number))),
+            _ => Err(invalid()),
+        },
+        _ if FUNCTIONS.contains(&name) => Err(invalid()),
+        _ => Err(CompileError::UnknownFunction(name.to_string())),
+    }
+}
+
+/// The tile holding the pointer, if the argument is a plain tile like `*4`.
+fn pointer_tile(argument: AnyExpressionType) -> Option<u8> {
+    let value = argument.value()?;
+    match value.downcast_ref::<Deref>()?.value() {
+        Ok(Reference::Pointer(tile)) => Some(tile),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Abs {
+    argument: Box<dyn Expression>,
+}
+
+impl Abs {
+    pub fn new(argument: Box<dyn Expression>) -> Self {
+        Self { argument }
+    }
+}
+
+impl Expression for Abs {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let argument = self.argument.to_command(labels, memory, inside_block)?;
+        //nothing else is worked out while the tile is used, so it can be given back straight away
+        let scratch = memory.allocate()?;
+        memory.release(&scratch);
+        let construct = labels.construct("abs");
+        let negative_label = labels.label(&construct, "negative");
+        let end_label = labels.label(&construct, "end");
+        
+        Ok(argument.into_iter()
+            .chain(vec![Command::JumpIfNegative(negative_label.reference()),
+                        Command::Jump(end_label.reference()),
+                        Command::Label(negative_label),
+                        Command::CopyTo(scratch.clone()),
+                        Command::Subtract(scratch.clone()),
+                        Command::Subtract(scratch),
+                        Command::Label(end_label)])
+            .collect())
+    }
+    
+    fn side_effects(&self) -> SideEffects {
+        self.argument.side_effects()
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.argument, memory);
+        known_constant(self.known_value(memory)?, memory)
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        self.argument.known_value(memory).map(i16::abs)
+    }
+    
+    impl_fns!(Expression);
+}
+
+impl_partialeq!(Abs, argument);
+
+#[derive(Debug, Clone)]
+pub struct Sign {
+    argument: Box<dyn Expression>,
+}
+
+impl Sign {
+    pub fn new(argument: Box<dyn Expression>) -> Self {
+        Self { argument }
+    }
+}
+
+impl Expression for Sign {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let one = memory.constant(1)?;
+        let minus_one = memory.constant(-1)?;
+        let construct = labels.construct("sign");
+        let negative_label = labels.label(&construct, "negative");
+        let end_label = labels.label(&construct, "end");
+        
+        Ok(self.argument.to_command(labels, memory, inside_block)?.into_iter()
+            .chain(vec![Command::JumpIfNegative(negative_label.reference()),
+                        Command::JumpIfZero(end_label.reference()),
+                        Command::CopyFrom(one),
+                        Command::Jump(end_label.reference()),
+                        Command::Label(negative_label),
+                        Command::CopyFrom(minus_one),
+                        Command::Label(end_label)])
+            .collect())
+    }
+    
+    fn side_effects(&self) -> SideEffects {
+        self.argument.side_effects()
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.argument, memory);
+        known_constant(self.known_value(memory)?, memory)
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        self.argument.known_value(memory).map(i16::signum)
+    }
+    
+    impl_fns!(Expression);
+}
+
+impl_partialeq!(Sign, argument);
+
+/// `min(left, right)` or `max(left, right)`
+#[derive(Debug, Clone)]
+pub struct Extremum {
+    left: Box<dyn Expression>,
+    right: Box<dyn Expression>,
+    max: bool,
+}
+
+impl Extremum {
+    pub fn new(left: Box<dyn Expression>, right: Box<dyn Expression>, max: bool) -> Self {
+        Self { left, right, max }
+    }
+    
+    /// Decides with `right - left`, for when there is only one scratch tile, which holds `left`.
+    /// This overflows when the two are more than 999 apart.
+    fn compare_by_difference(&self, scratch: Reference, construct: &Construct, labels: &mut LabelGenerator) -> Vec<Command> {
+        let right_smaller_label = labels.label(construct, "right_smaller");
+        let end_label = labels.label(construct, "end");
+        
+        //right - left is in hand when deciding, adding left back gives right
+        let (right_smaller, right_not_smaller) = if self.max {
+            (Command::CopyFrom(scratch.clone()), Command::Add(scratch.clone()))
+        } else {
+            (Command::Add(scratch.clone()), Command::CopyFrom(scratch.clone()))
+        };
+        
+        vec![Command::Subtract(scratch),
+             Command::JumpIfNegative(right_smaller_label.reference()),
+             right_not_smaller,
+             Command::Jump(end_label.reference()),
+             Command::Label(right_smaller_label),
+             right_smaller,
+             Command::Label(end_label)]
+    }
+}
+
+impl Expression for Extremum {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let left = self.left.to_command(labels, memory, inside_block)?;
+        let scratch = memory.allocate()?;
+        let right = self.right.to_command(labels, memory, inside_block)?;
+        let right_scratch = memory.allocate();
+        memory.release(&scratch);
+        let construct = labels.construct(if self.max { "max" } else { "min" });
+        let start = left.into_iter()
+            .chain(vec![Command::CopyTo(scratch.clone())])
+            .chain(right);
+        
+        let right_scratch = match right_scratch {
+            Ok(right_scratch) => {
+                memory.release(&right_scratch);
+                right_scratch
+            }
+            Err(_) => return Ok(start.chain(self.compare_by_difference(scratch, &construct, labels)).collect()),
+        };
+        
+        //left - right can't overflow when both have the same sign. Otherwise the one that is
+        //negative is the smaller one.
+        let right_negative = labels.label(&construct, "right_negative");
+        let same_sign = labels.label(&construct, "same_sign");
+        let left_not_smaller = labels.label(&construct, "left_not_smaller");
+        let left_smaller = labels.label(&construct, "left_smaller");
+        let end = labels.label(&construct, "end");
+        let (pick_left, pick_right) = (Command::CopyFrom(scratch.clone()), Command::CopyFrom(right_scratch.clone()));
+        let (if_left_smaller, otherwise) = if self.max { (pick_right, pick_left) } else { (pick_left, pick_right) };
+        
+        Ok(start
+            .chain(vec![Command::CopyTo(right_scratch.clone()),
+                        Command::JumpIfNegative(right_negative.reference()),
+                        Command::CopyFrom(scratch.clone()),
+                        Command::JumpIfNegative(left_smaller.reference()),
+                        Command::Jump(same_sign.reference()),
+                        Command::Label(right_negative),
+                        Command::CopyFrom(scratch.clone()),
+                        Command::JumpIfNegative(same_sign.reference()),
+                        Command::Jump(left_not_smaller.reference()),
+                        Command::Label(same_sign),
+                        Command::Subtract(right_scratch),
+                        Command::JumpIfNegative(left_smaller.reference()),
+                        Command::Label(left_not_smaller),
+                        otherwise,
+                        Command::Jump(end.reference()),
+                        Command::Label(left_smaller),
+                        if_left_smaller,
+                        Command::Label(end)])
+            .collect())
+    }
+    
+    fn side_effects(&self) -> SideEffects {
+        self.left.side_effects().max(self.right.side_effects())
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.left, memory);
+        simplify_in_place(&mut self.right, memory);
+        known_constant(self.known_value(memory)?, memory)
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        let (left, right) = (self.left.known_value(memory)?, self.right.known_value(memory)?);
+        Some(if self.max { left.max(right) } else { left.min(right) })
+    }
+    
+    impl_fns!(Expression);
+}
+
+impl_partialeq!(Extremum, left, right, max);
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ReadString {
+    pointer: u8,
+}
+
+impl ReadString {
+    pub fn new(pointer: u8) -> Self {
+        Self { pointer }
+    }
+}
+
+impl Expression for ReadString {
+    fn to_command(&self, labels: &mut LabelGenerator, _memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("read_string");
+        let top_label = labels.label(&construct, "top");
+        let end_label = labels.label(&construct, "end");
+        
+        Ok(vec![Command::Label(top_label.clone()),
+                Command::Inbox,
+                Command::CopyTo(Reference::PointerPointer(self.pointer)),
+                Command::JumpIfZero(end_label.reference()),
+                Command::Increment(Reference::Pointer(self.pointer)),
+                Command::Jump(top_label.reference()),
+                Command::Label(end_label)])
+    }
+    
+    impl_fns!(Expression);
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct WriteString {
+    pointer: u8,
+}
+
+impl WriteString {
+    pub fn new(pointer: u8) -> Self {
+        Self { pointer }
+    }
+}
+
+impl Expression for WriteString {
+    fn to_command(&self, labels: &mut LabelGenerator, _memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("write_string");
+        let top_label = labels.label(&construct, "top");
+        let end_label = labels.label(&construct, "end");
+        
+        Ok(vec![Command::Label(top_label.clone()),
+                Command::CopyFrom(Reference::PointerPointer(self.pointer)),
+                Command::JumpIfZero(end_label.reference()),
+                Command::Outbox,
+                Command::Increment(Reference::Pointer(self.pointer)),
+                Command::Jump(top_label.reference()),
+                Command::Label(end_label)])
+    }
+    
+    impl_fns!(Expression);
+}
+
+#[derive(Debug, Clone)]
+pub struct Countdown {
+    argument: Box<dyn Expression>,
+}
+
+impl Countdown {
+    pub fn new(argument: Box<dyn Expression>) -> Self {
+        Self { argument }
+    }
+}
+
+impl Expression for Countdown {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let argument = self.argument.to_command(labels, memory, inside_block)?;
+        let scratch = memory.allocate()?;
+        memory.release(&scratch);
+        let construct = labels.construct("countdown");
+        let top_label = labels.label(&construct, "top");
+        let up_label = labels.label(&construct, "up");
+        let end_label = labels.label(&construct, "end");
+        
+        Ok(argument.into_iter()
+            .chain(vec![Command::CopyTo(scratch.clone()),
+                        Command::Label(top_label.clone()),
+                        Command::Outbox,
+                        Command::CopyFrom(scratch.clone()),
+                        Command::JumpIfZero(end_label.reference()),
+                        Command::JumpIfNegative(up_label.reference()),
+                        Command::Decrement(scratch.clone()),
+                        Command::Jump(top_label.reference()),
+                        Command::Label(up_label),
+                        Command::Increment(scratch),
+                        Command::Jump(top_label.reference()),
+                        Command::Label(end_label)])
+            .collect())
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.argument, memory);
+        None
+    }
+    
+    impl_fns!(Expression);
+}
+
+impl_partialeq!(Countdown, argument);
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Comment {
+    number: u8,
+}
+
+impl Comment {
+    pub fn new(number: u8) -> Self {
+        Self { number }
+    }
+}
+
+impl Expression for Comment {
+    fn to_command(&self, _labels: &mut LabelGenerator, _memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        Ok(vec![Command::Comment(self.number)])
+    }
+    
+    impl_fns!(Expression);
+}
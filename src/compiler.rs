@@ -1,7 +1,9 @@
 use std::fmt::{Display, Formatter, Error};
 use std::fmt;
+use std::collections::{HashMap, HashSet};
+use crate::CompileError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     Inbox,
     Outbox,
@@ -15,6 +17,7 @@ pub enum Command {
     JumpIfZero(LabelRef),
     JumpIfNegative(LabelRef),
     Label(Label),
+    Comment(u8),
 }
 
 impl Display for Command {
@@ -32,11 +35,12 @@ impl Display for Command {
             Command::JumpIfZero(label) => format!("JUMPZ\t{}", label),
             Command::JumpIfNegative(label) => format!("JUMPN\t{}", label),
             Command::Label(label) => format!("{}", label),
+            Command::Comment(number) => format!("COMMENT\t{}", number),
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Reference {
     Number(u8),
     Pointer(u8),
@@ -53,16 +57,103 @@ impl Display for Reference {
     }
 }
 
-#[derive(Debug)]
+/// Which of the two scores of a level the program is made for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Goal {
+    /// As few commands as possible
+    #[default]
+    Size,
+    /// As few steps as possible, even if that takes more commands
+    Speed,
+}
+
+/// The tiles that the compiler is allowed to make assumptions about: the intermediate tile used
+/// when adding and subtracting, and the tiles that hold a known constant (`#constant`). It also
+/// knows the `Goal`, since it is handed to all of the code generation anyway.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    /// The scratch tiles that aren't holding a temporary right now, lowest first
+    free_scratch: Vec<u8>,
+    scratch_size: usize,
+    constants: HashMap<i16, u8>,
+    goal: Goal,
+}
+
+impl Memory {
+    pub fn new(scratch: Vec<u8>, constants: Vec<(i16, u8)>, goal: Goal) -> Self {
+        let mut free_scratch = scratch;
+        free_scratch.sort_unstable();
+        free_scratch.dedup();
+        Self { scratch_size: free_scratch.len(), free_scratch, constants: constants.into_iter().collect(), goal }
+    }
+    
+    pub fn goal(&self) -> Goal {
+        self.goal
+    }
+    
+    /// Takes a scratch tile to hold a temporary in. It has to be given back with `release` once the
+    /// temporary isn't needed anymore, so the code worked out in between can't use the same tile.
+    pub fn allocate(&mut self) -> Result<Reference, CompileError> {
+        if self.free_scratch.is_empty() {
+            return Err(CompileError::ScratchExhausted(self.scratch_size));
+        }
+        
+        Ok(Reference::Pointer(self.free_scratch.remove(0)))
+    }
+    
+    pub fn release(&mut self, tile: &Reference) {
+        if let Reference::Pointer(tile) = tile {
+            let index = self.free_scratch.binary_search(tile).unwrap_or_else(|e| e);
+            self.free_scratch.insert(index, *tile);
+        }
+    }
+    
+    pub fn constant(&self, value: i16) -> Result<Reference, CompileError> {
+        self.constants.get(&value)
+            .map(|tile| Reference::Pointer(*tile))
+            .ok_or(CompileError::MissingConstant(value))
+    }
+    
+    /// Fails if any of `commands` writes to a tile declared with `#constant`, since the compiler
+    /// counts on those keeping their value
+    pub fn check_writes(&self, commands: &[Command]) -> Result<(), CompileError> {
+        for command in commands {
+            if let Command::CopyTo(tile) | Command::Increment(tile) | Command::Decrement(tile) = command {
+                if let Some(value) = self.constant_value(tile) {
+                    return Err(CompileError::WriteToConstant { tile: tile.clone(), value });
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// The value `tile` is known to hold, if it was declared with `#constant`. Code that writes to
+    /// the tile is rejected by `check_writes`, so reads of it can be replaced by the value. A write
+    /// through a pointer can't be checked, and is up to the programmer to avoid.
+    pub fn constant_value(&self, tile: &Reference) -> Option<i16> {
+        match tile {
+            Reference::Pointer(tile) => self.constants.iter()
+                .find(|(_, e)| *e == tile)
+                .map(|(value, _)| *value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Label {
-    count: u8,
+    name: String,
 }
 
 impl Label {
-    pub fn new(count: &mut u8) -> Self {
-        let out = Self { count: *count };
-        *count += 1;
-        out
+    /// A label with a name picked by the programmer or the `LabelGenerator`.
+    pub fn named(name: &str) -> Self {
+        Self { name: name.to_string() }
+    }
+    
+    pub fn name(&self) -> &str {
+        &self.name
     }
     
     pub fn reference(&self) -> LabelRef {
@@ -72,29 +163,269 @@ impl Label {
 
 impl Display for Label {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        write!(f, "{}:", number_to_chars(&self.count))
+        write!(f, "{}:", self.name)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LabelRef {
-    count: u8
+    name: String,
 }
 
 impl LabelRef {
     pub fn new(label: &Label) -> Self {
-        Self { count: label.count }
+        Self { name: label.name.clone() }
+    }
+    
+    pub fn named(name: &str) -> Self {
+        Self { name: name.to_string() }
+    }
+    
+    pub fn name(&self) -> &str {
+        &self.name
     }
 }
 
 impl Display for LabelRef {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        write!(f, "{}", number_to_chars(&self.count))
+        write!(f, "{}", self.name)
     }
 }
 
-fn number_to_chars(number: &u8) -> String {
-    let small = number % 16;
-    let big = number / 16;
-    [(big + 97) as char, (small + 97) as char].iter().collect()
+/// Makes sure that no two labels in the finished program share a name, which could happen if a
+/// user defined label looks like one of the generated ones.
+pub fn check_labels(commands: &[Command]) -> Result<(), CompileError> {
+    let mut seen = HashSet::new();
+    for command in commands {
+        if let Command::Label(label) = command {
+            if !seen.insert(label.name()) {
+                return Err(CompileError::DuplicateLabel(label.name().to_string()));
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// How the labels made by a `LabelGenerator` are named
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelStyle {
+    /// Short names made of letters, `aa`, `ab` and so on, for pasting into the game
+    Compact,
+    /// Names that say what the label is for, like `while_3_top`
+    Descriptive,
+}
+
+/// Makes the labels for the jumps that the compiler adds. Labels belong to a construct, like a
+/// `while` loop, and in descriptive mode they are named after the construct, its number and what
+/// the label is for. Names that are reserved, like the labels the programmer declared, are
+/// skipped.
+#[derive(Debug)]
+pub struct LabelGenerator {
+    style: LabelStyle,
+    labels: usize,
+    constructs: usize,
+    reserved: HashSet<String>,
+}
+
+/// One construct that labels are made for, see `LabelGenerator::construct`
+#[derive(Debug)]
+pub struct Construct {
+    kind: &'static str,
+    number: usize,
+}
+
+impl LabelGenerator {
+    pub fn new(style: LabelStyle) -> Self {
+        Self { style, labels: 0, constructs: 0, reserved: HashSet::new() }
+    }
+    
+    /// Makes sure that no label made from now on is called any of `names`
+    pub fn reserve(&mut self, names: impl IntoIterator<Item = String>) {
+        self.reserved.extend(names);
+    }
+    
+    pub fn construct(&mut self, kind: &'static str) -> Construct {
+        self.constructs += 1;
+        Construct { kind, number: self.constructs }
+    }
+    
+    /// A new label in `construct`, `role` says what it is used for
+    pub fn label(&mut self, construct: &Construct, role: &str) -> Label {
+        let mut attempt = 1;
+        loop {
+            self.labels += 1;
+            let name = match self.style {
+                LabelStyle::Compact => compact_name(self.labels - 1),
+                LabelStyle::Descriptive if attempt == 1 => format!("{}_{}_{}", construct.kind, construct.number, role),
+                LabelStyle::Descriptive => format!("{}_{}_{}_{}", construct.kind, construct.number, role, attempt),
+            };
+            if !self.reserved.contains(&name) {
+                return Label::named(&name);
+            }
+            attempt += 1;
+        }
+    }
+}
+
+/// Writes `number` with the letters `a` to `p` as digits, using at least two of them.
+fn compact_name(number: usize) -> String {
+    let mut digits = vec![];
+    let mut rest = number;
+    while rest > 0 || digits.len() < 2 {
+        digits.push((b'a' + (rest % 16) as u8) as char);
+        rest /= 16;
+    }
+    
+    digits.iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parser::parse_tokens;
+    use crate::preprocessor::Location;
+    use crate::parser2::{simplify_block, VALUE_RANGE};
+    
+    fn compile(source: &str, style: LabelStyle) -> Result<Vec<Command>, CompileError> {
+        compile_with(source, style, Memory::new(vec![], vec![], Goal::Size))
+    }
+    
+    fn compile_with(source: &str, style: LabelStyle, mut memory: Memory) -> Result<Vec<Command>, CompileError> {
+        let (tokens, lines) = lex(source).unwrap();
+        let locations = lines.into_iter().map(|line| Location { file: String::from("test"), line }).collect();
+        let mut ast = parse_tokens(tokens, locations)?;
+        simplify_block(&mut ast.root, &memory);
+        let mut labels = LabelGenerator::new(style);
+        labels.reserve(ast.labels.iter().cloned());
+        let mut commands = Vec::new();
+        for expression in &ast.root {
+            commands.extend(expression.to_command(&mut labels, &mut memory, None)?);
+        }
+        check_labels(&commands)?;
+        Ok(commands)
+    }
+    
+    /// Runs a program without inbox on a floor of tiles 0 to 24, panicking on values HRM can't hold.
+    fn run(commands: &[Command], floor: &[(u8, i16)]) -> Vec<i16> {
+        let mut tiles = [0i16; 25];
+        for &(tile, value) in floor {
+            tiles[tile as usize] = value;
+        }
+        let find = |label: &LabelRef| commands.iter()
+            .position(|e| matches!(e, Command::Label(l) if l.name() == label.name()))
+            .unwrap();
+        let (mut hand, mut output, mut position) = (0, Vec::new(), 0);
+        while let Some(command) = commands.get(position) {
+            position += 1;
+            match command {
+                Command::CopyFrom(Reference::Pointer(tile)) => hand = tiles[*tile as usize],
+                Command::CopyTo(Reference::Pointer(tile)) => tiles[*tile as usize] = hand,
+                Command::Add(Reference::Pointer(tile)) => hand += tiles[*tile as usize],
+                Command::Subtract(Reference::Pointer(tile)) => hand -= tiles[*tile as usize],
+                Command::Outbox => output.push(hand),
+                Command::Jump(label) => position = find(label),
+                Command::JumpIfZero(label) => if hand == 0 { position = find(label) },
+                Command::JumpIfNegative(label) => if hand < 0 { position = find(label) },
+                Command::Label(_) => {}
+                e => panic!("{:?} isn't supported", e),
+            }
+            assert!(VALUE_RANGE.contains(&hand), "{} is out of range", hand);
+        }
+        output
+    }
+    
+    fn label_names(commands: &[Command]) -> Vec<&str> {
+        commands.iter()
+            .filter_map(|e| match e {
+                Command::Label(label) => Some(label.name()),
+                _ => None,
+            })
+            .collect()
+    }
+    
+    #[test]
+    fn user_label_named_like_generated_one() {
+        //the loop would get `aa` as well if it wasn't reserved
+        let commands = compile("label aa: loop { output(input()); }", LabelStyle::Compact).unwrap();
+        let names = label_names(&commands);
+        assert_eq!(names.iter().filter(|e| **e == "aa").count(), 1);
+        assert!(names.len() > 1);
+        
+        let commands = compile("label loop_1_top: loop { goto loop_1_top; }", LabelStyle::Descriptive).unwrap();
+        assert!(label_names(&commands).contains(&"loop_1_top_2"));
+    }
+    
+    #[test]
+    fn label_errors_are_located() {
+        let located = |error, line| Err(CompileError::Located {
+            error: Box::new(error),
+            location: Location { file: String::from("test"), line },
+        });
+        let source = "label a:\noutput(input());\ngoto b;";
+        assert_eq!(compile(source, LabelStyle::Compact), located(CompileError::UndefinedLabel(String::from("b")), 2));
+        let source = "output(input());\ngoto a;\nloop {\n    label a: output(input());\n}";
+        assert_eq!(compile(source, LabelStyle::Compact), located(CompileError::JumpIntoBlock(String::from("a")), 1));
+        let source = "label a:\nloop {\n    label a: output(input());\n}";
+        assert_eq!(compile(source, LabelStyle::Compact), located(CompileError::DuplicateLabel(String::from("a")), 2));
+    }
+    
+    #[test]
+    fn scratch_exhaustion_is_located() {
+        let source = "loop {\n    output(input());\n    output(input() + input());\n}";
+        assert_eq!(compile(source, LabelStyle::Compact), Err(CompileError::Located {
+            error: Box::new(CompileError::ScratchExhausted(0)),
+            location: Location { file: String::from("test"), line: 2 },
+        }));
+    }
+    
+    #[test]
+    fn constant_tiles_are_not_written() {
+        let memory = || Memory::new(vec![6], vec![(1, 6)], Goal::Size);
+        let written = |line| Err(CompileError::Located {
+            error: Box::new(CompileError::WriteToConstant { tile: Reference::Pointer(6), value: 1 }),
+            location: Location { file: String::from("test"), line },
+        });
+        assert_eq!(compile_with("output(input());\n*6 = input();", LabelStyle::Compact, memory()), written(1));
+        assert_eq!(compile_with("loop {\n    *6++;\n}", LabelStyle::Compact, memory()), written(1));
+        assert_eq!(compile_with("asm {\n    COPYTO 6;\n}", LabelStyle::Compact, memory()), written(0));
+        //the scratch tile is the constant tile as well
+        assert_eq!(compile_with("output(input() + input());", LabelStyle::Compact, memory()), written(0));
+        assert!(compile_with("*5 = input();\noutput(*5 + *6);", LabelStyle::Compact, memory()).is_ok());
+    }
+    
+    #[test]
+    fn min_max_of_far_apart_values() {
+        let memory = Memory::new(vec![20, 21], vec![], Goal::Size);
+        let commands = compile_with("output(max(*1, *2));\noutput(min(*1, *2));", LabelStyle::Compact, memory).unwrap();
+        for &(a, b) in &[(999, -999), (-999, 999), (-5, -9), (-9, -5), (0, -1), (4, 9), (7, 7)] {
+            assert_eq!(run(&commands, &[(1, a), (2, b)]), vec![a.max(b), a.min(b)]);
+        }
+    }
+    
+    #[test]
+    fn written_constant_tile_is_not_folded_silently() {
+        //`*6 + 1` would be folded to 2, which is wrong once the input is put on tile 6
+        let memory = Memory::new(vec![0], vec![(1, 6), (2, 7)], Goal::Size);
+        let result = compile_with("*6 = input(); output(*6 + 1);", LabelStyle::Compact, memory);
+        assert!(matches!(result, Err(CompileError::Located { error, .. }) if matches!(*error, CompileError::WriteToConstant { .. })));
+        
+        let memory = Memory::new(vec![0], vec![(1, 6), (2, 7)], Goal::Size);
+        let commands = compile_with("output(*6 + 1);", LabelStyle::Compact, memory).unwrap();
+        assert_eq!(commands, vec![Command::CopyFrom(Reference::Pointer(7)), Command::Outbox]);
+    }
+    
+    #[test]
+    fn generator_skips_reserved_names() {
+        let mut labels = LabelGenerator::new(LabelStyle::Compact);
+        labels.reserve(vec![String::from("aa"), String::from("ab")]);
+        let construct = labels.construct("loop");
+        assert_eq!(labels.label(&construct, "start").name(), "ac");
+        
+        let mut labels = LabelGenerator::new(LabelStyle::Descriptive);
+        labels.reserve(vec![String::from("loop_1_start")]);
+        let construct = labels.construct("loop");
+        assert_eq!(labels.label(&construct, "start").name(), "loop_1_start_2");
+    }
 }
\ No newline at end of file
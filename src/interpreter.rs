@@ -0,0 +1,119 @@
+//! Runs straight-line HRM commands the way the game does, so two runs of commands can be compared
+//! by what they do. Only numbers are modelled, not letters.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use crate::compiler::{Command, Reference};
+use crate::parser2::VALUE_RANGE;
+
+/// Something that happens outside of the floor, in the order it happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Inbox,
+    Outbox(i16),
+}
+
+/// Why the game stops the program with an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    EmptyHand,
+    EmptyTile,
+    /// A value outside of -999 to 999
+    Overflow,
+    /// A tile that isn't on the floor, or a pointer that isn't a tile number
+    NoTile,
+    /// A jump or label, which straight-line runs can't have
+    NotStraightLine,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Worker {
+    pub hand: Option<i16>,
+    pub floor: Vec<Option<i16>>,
+    pub inbox: VecDeque<i16>,
+    pub events: Vec<Event>,
+    /// Whether the program ended because the inbox was empty
+    pub stopped: bool,
+}
+
+impl Worker {
+    pub fn new(hand: Option<i16>, floor: Vec<Option<i16>>, inbox: Vec<i16>) -> Self {
+        Worker { hand, floor, inbox: inbox.into(), events: Vec::new(), stopped: false }
+    }
+    
+    /// Runs the commands until they end or the inbox runs out
+    pub fn run(&mut self, commands: &[Command]) -> Result<(), Fault> {
+        commands.iter().try_for_each(|command| self.step(command))
+    }
+    
+    /// Runs one command, doing nothing once the program has stopped
+    pub fn step(&mut self, command: &Command) -> Result<(), Fault> {
+        if self.stopped {
+            return Ok(());
+        }
+        
+        match command {
+            Command::Inbox => match self.inbox.pop_front() {
+                Some(value) => {
+                    self.hand = Some(value);
+                    self.events.push(Event::Inbox);
+                }
+                None => self.stopped = true,
+            },
+            Command::Outbox => {
+                let value = self.hand.take().ok_or(Fault::EmptyHand)?;
+                self.events.push(Event::Outbox(value));
+            }
+            Command::CopyFrom(tile) => self.hand = Some(self.read(tile)?),
+            Command::CopyTo(tile) => {
+                let value = self.hand.ok_or(Fault::EmptyHand)?;
+                *self.tile(tile)? = Some(value);
+            }
+            Command::Add(tile) | Command::Subtract(tile) => {
+                let hand = self.hand.ok_or(Fault::EmptyHand)?;
+                let value = self.read(tile)?;
+                let result = match command {
+                    Command::Add(_) => hand + value,
+                    _ => hand - value,
+                };
+                self.hand = Some(in_range(result)?);
+            }
+            Command::Increment(tile) | Command::Decrement(tile) => {
+                let value = self.read(tile)?;
+                let result = in_range(match command {
+                    Command::Increment(_) => value + 1,
+                    _ => value - 1,
+                })?;
+                *self.tile(tile)? = Some(result);
+                self.hand = Some(result);
+            }
+            Command::Comment(_) => {}
+            Command::Jump(_) | Command::JumpIfZero(_) | Command::JumpIfNegative(_) |
+            Command::Label(_) => return Err(Fault::NotStraightLine),
+        }
+        
+        Ok(())
+    }
+    
+    fn read(&mut self, tile: &Reference) -> Result<i16, Fault> {
+        self.tile(tile)?.ok_or(Fault::EmptyTile)
+    }
+    
+    /// The tile that `tile` refers to, following the pointer for `[n]`
+    fn tile(&mut self, tile: &Reference) -> Result<&mut Option<i16>, Fault> {
+        let index = match tile {
+            Reference::Pointer(tile) => *tile as usize,
+            Reference::PointerPointer(tile) => {
+                let pointer = self.floor.get(*tile as usize).ok_or(Fault::NoTile)?.ok_or(Fault::EmptyTile)?;
+                usize::try_from(pointer).map_err(|_| Fault::NoTile)?
+            }
+            Reference::Number(_) => return Err(Fault::NoTile),
+        };
+        
+        self.floor.get_mut(index).ok_or(Fault::NoTile)
+    }
+}
+
+fn in_range(value: i16) -> Result<i16, Fault> {
+    Some(value).filter(|e| VALUE_RANGE.contains(e)).ok_or(Fault::Overflow)
+}
\ No newline at end of file
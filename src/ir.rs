@@ -0,0 +1,422 @@
+//! The program as a control flow graph of basic blocks. Each block is a run of commands that is
+//! always run from the start to the end, followed by a terminator saying where to go next. The
+//! commands from the code generation are turned into a graph with `Cfg::from_commands`, the
+//! passes work on the graph, and `Cfg::to_commands` turns it back into HRM commands.
+
+use std::collections::{HashMap, HashSet};
+use crate::compiler::{Command, Goal, Label, LabelGenerator, LabelRef};
+use crate::CompileError;
+use crate::{accumulator, liveness};
+
+/// The index of a block in `Cfg::blocks`
+pub type BlockId = usize;
+
+/// Blocks with up to this many commands are copied into the blocks that jump to them when
+/// optimising for speed
+pub const DUPLICATE_LIMIT: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Goes to the first block if the hand is zero and to the second one otherwise
+    JumpIfZero(BlockId, BlockId),
+    /// Goes to the first block if the hand is negative and to the second one otherwise
+    JumpIfNegative(BlockId, BlockId),
+    /// Goes on to the block without a jump, which is the next block unless the blocks are moved
+    FallThrough(BlockId),
+    /// The end of the program
+    Halt,
+}
+
+impl Terminator {
+    /// The blocks that can come after this one
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) | Terminator::FallThrough(target) => vec![*target],
+            Terminator::JumpIfZero(taken, not_taken) |
+            Terminator::JumpIfNegative(taken, not_taken) => vec![*taken, *not_taken],
+            Terminator::Halt => vec![],
+        }
+    }
+    
+    /// Whether both go to the same blocks in the same cases, a jump and a fall through count as
+    /// the same
+    fn same_targets(&self, other: &Terminator) -> bool {
+        match (self, other) {
+            (Terminator::Jump(target) | Terminator::FallThrough(target),
+             Terminator::Jump(other) | Terminator::FallThrough(other)) => target == other,
+            _ => self == other,
+        }
+    }
+    
+    /// Changes every block this goes to into `change(block)`
+    fn map_targets(&mut self, change: impl Fn(BlockId) -> BlockId) {
+        match self {
+            Terminator::Jump(target) | Terminator::FallThrough(target) => *target = change(*target),
+            Terminator::JumpIfZero(taken, not_taken) |
+            Terminator::JumpIfNegative(taken, not_taken) => {
+                *taken = change(*taken);
+                *not_taken = change(*not_taken);
+            }
+            Terminator::Halt => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// The labels the block started with, the first one is used for jumps to the block
+    pub labels: Vec<Label>,
+    /// The commands of the block, which are never labels or jumps
+    pub commands: Vec<Command>,
+    pub terminator: Terminator,
+}
+
+/// The blocks are kept in the order they are written out in, and the first one is where the
+/// program starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+impl Cfg {
+    pub fn from_commands(commands: Vec<Command>) -> Result<Self, CompileError> {
+        //the blocks with their jumps still pointing at label names
+        let mut blocks: Vec<(Vec<Label>, Vec<Command>, Option<Command>)> = vec![(vec![], vec![], None)];
+        
+        for command in commands {
+            let current = blocks.last_mut().unwrap();
+            match command {
+                Command::Label(label) => {
+                    if current.1.is_empty() && current.2.is_none() {
+                        current.0.push(label);
+                    } else {
+                        blocks.push((vec![label], vec![], None));
+                    }
+                }
+                Command::Jump(_) | Command::JumpIfZero(_) | Command::JumpIfNegative(_) => {
+                    current.2 = Some(command);
+                    blocks.push((vec![], vec![], None));
+                }
+                command => {
+                    if current.2.is_some() {
+                        blocks.push((vec![], vec![command], None));
+                    } else {
+                        current.1.push(command);
+                    }
+                }
+            }
+        }
+        
+        let ids: HashMap<String, BlockId> = blocks.iter()
+            .enumerate()
+            .flat_map(|(id, block)| block.0.iter().map(move |label| (label.name().to_string(), id)))
+            .collect();
+        let target = |label: &LabelRef| ids.get(label.name())
+            .copied()
+            .ok_or_else(|| CompileError::UndefinedLabel(label.name().to_string()));
+        
+        let count = blocks.len();
+        let mut out = Vec::new();
+        for (id, (labels, commands, jump)) in blocks.into_iter().enumerate() {
+            //a block that ends with a jump is always followed by another one
+            let terminator = match &jump {
+                Some(Command::Jump(label)) => Terminator::Jump(target(label)?),
+                Some(Command::JumpIfZero(label)) => Terminator::JumpIfZero(target(label)?, id + 1),
+                Some(Command::JumpIfNegative(label)) => Terminator::JumpIfNegative(target(label)?, id + 1),
+                _ if id + 1 < count => Terminator::FallThrough(id + 1),
+                _ => Terminator::Halt,
+            };
+            out.push(Block { labels, commands, terminator });
+        }
+        
+        Ok(Self { blocks: out })
+    }
+    
+    /// Runs the optimisations on the graph, the ones that trade size for speed or the other way
+    /// round depend on `goal`.
+    pub fn optimize(&mut self, goal: Goal) {
+        self.thread_jumps();
+        self.remove_unreachable();
+        match goal {
+            Goal::Size => self.merge_tails(true),
+            Goal::Speed => {
+                self.merge_tails(false);
+                self.duplicate_jump_targets();
+            }
+        }
+        accumulator::remove_redundant_loads(self);
+        liveness::remove_dead_stores(self);
+        //removing commands can leave blocks that are the same now
+        if goal == Goal::Size {
+            self.thread_jumps();
+            self.merge_tails(true);
+            self.share_blocks();
+        }
+        self.thread_jumps();
+        self.remove_unreachable();
+        self.remove_unused_labels();
+    }
+    
+    /// The block that `id` ends with an unconditional jump to, if it has to jump there
+    fn jumps_to(&self, id: BlockId) -> Option<BlockId> {
+        match self.blocks[id].terminator {
+            Terminator::Jump(target) => Some(target),
+            Terminator::FallThrough(target) if target != id + 1 => Some(target),
+            _ => None,
+        }
+    }
+    
+    /// Puts `block` in at `id`, moving the blocks from there on back by one
+    fn insert_block(&mut self, id: BlockId, block: Block) {
+        for other in &mut self.blocks {
+            other.terminator.map_targets(|target| if target >= id { target + 1 } else { target });
+        }
+        self.blocks.insert(id, block);
+    }
+    
+    /// When two blocks end with the same commands and then go on to the same block, one of them
+    /// jumps into the end of the other instead. Only blocks that end with a jump anyway are made
+    /// to jump, but if the other block ends with a jump too, that costs a step, so that is only
+    /// done if `extra_steps` is set.
+    pub fn merge_tails(&mut self, extra_steps: bool) {
+        while let Some((keep, drop, length)) = self.find_common_tail(extra_steps) {
+            let block = &mut self.blocks[keep];
+            let at = block.commands.len() - length;
+            let tail = Block {
+                labels: vec![],
+                commands: block.commands.split_off(at),
+                terminator: Terminator::Halt,
+            };
+            self.insert_block(keep + 1, tail);
+            self.blocks[keep + 1].terminator = std::mem::replace(&mut self.blocks[keep].terminator, Terminator::FallThrough(keep + 1));
+            
+            let drop = if drop > keep { drop + 1 } else { drop };
+            let block = &mut self.blocks[drop];
+            block.commands.truncate(block.commands.len() - length);
+            block.terminator = Terminator::Jump(keep + 1);
+        }
+    }
+    
+    /// A block that goes on to some block, one that jumps to the same block and how many commands
+    /// both of them end with. Merges that cost a step are only made when there are no free ones,
+    /// and then the block further down keeps the commands, since the code before a loop usually
+    /// runs less often than the code in it.
+    fn find_common_tail(&self, extra_steps: bool) -> Option<(BlockId, BlockId, usize)> {
+        let mut best: Option<((bool, bool), BlockId, BlockId, usize)> = None;
+        for (keep, block) in self.blocks.iter().enumerate() {
+            let (target, free) = match block.terminator {
+                Terminator::FallThrough(target) if target == keep + 1 => (target, true),
+                Terminator::Jump(target) | Terminator::FallThrough(target) if extra_steps => (target, false),
+                _ => continue,
+            };
+            for drop in (0..self.blocks.len()).filter(|drop| *drop != keep && self.jumps_to(*drop) == Some(target)) {
+                let length = block.commands.iter().rev()
+                    .zip(self.blocks[drop].commands.iter().rev())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let rank = (free, keep > drop);
+                let better = match &best {
+                    Some((best_rank, ..)) => rank > *best_rank,
+                    None => true,
+                };
+                if length > 0 && better {
+                    best = Some((rank, keep, drop, length));
+                }
+            }
+        }
+        
+        best.map(|(_, keep, drop, length)| (keep, drop, length))
+    }
+    
+    /// When two blocks do the same and then go to the same places, like two tests that decide
+    /// between the same two branches, everything that goes to one of them goes to the other one
+    /// instead. A block that ran into the dropped one has to jump now, so this is only done when
+    /// it makes the program shorter.
+    pub fn share_blocks(&mut self) {
+        loop {
+            let size = self.size();
+            let count = self.blocks.len();
+            let shared = (0..count)
+                .flat_map(|keep| (0..count).map(move |drop| (keep, drop)))
+                .filter(|&(keep, drop)| keep != drop
+                    && self.blocks[keep].commands == self.blocks[drop].commands
+                    && self.blocks[keep].terminator.same_targets(&self.blocks[drop].terminator))
+                .map(|(keep, drop)| {
+                    let mut cfg = self.clone();
+                    for block in &mut cfg.blocks {
+                        block.terminator.map_targets(|target| if target == drop { keep } else { target });
+                    }
+                    cfg.remove_unreachable();
+                    cfg
+                })
+                .find(|cfg| cfg.size() < size);
+            match shared {
+                Some(cfg) => *self = cfg,
+                None => break,
+            }
+        }
+    }
+    
+    /// How many commands `to_commands` writes out, not counting the labels
+    fn size(&self) -> usize {
+        let last = self.blocks.len().saturating_sub(1);
+        self.blocks.iter()
+            .enumerate()
+            .map(|(id, block)| block.commands.len() + match block.terminator {
+                Terminator::Jump(_) => 1,
+                Terminator::JumpIfZero(_, not_taken) |
+                Terminator::JumpIfNegative(_, not_taken) => 1 + (not_taken != id + 1) as usize,
+                Terminator::FallThrough(target) => (target != id + 1) as usize,
+                Terminator::Halt => (id != last) as usize,
+            })
+            .sum()
+    }
+    
+    /// Copies short blocks that end with a jump into the blocks that jump to them, which saves the
+    /// jump between them.
+    pub fn duplicate_jump_targets(&mut self) {
+        for id in 0..self.blocks.len() {
+            let target = match self.jumps_to(id) {
+                Some(target) if target != id => target,
+                _ => continue,
+            };
+            if self.blocks[target].commands.len() <= DUPLICATE_LIMIT && self.jumps_to(target).is_some() {
+                let copy = self.blocks[target].clone();
+                self.blocks[id].commands.extend(copy.commands);
+                self.blocks[id].terminator = copy.terminator;
+            }
+        }
+    }
+    
+    /// Makes jumps that land on a block that does nothing but go somewhere else go straight there.
+    pub fn thread_jumps(&mut self) {
+        let forwards: Vec<Option<BlockId>> = self.blocks.iter()
+            .map(|block| match block.terminator {
+                Terminator::Jump(target) | Terminator::FallThrough(target) if block.commands.is_empty() => Some(target),
+                _ => None,
+            })
+            .collect();
+        //follows the chain of empty blocks, stopping if it goes round in a circle
+        let destination = |start: BlockId| {
+            let mut seen = HashSet::new();
+            let mut current = start;
+            while let Some(next) = forwards[current] {
+                if !seen.insert(current) {
+                    break;
+                }
+                current = next;
+            }
+            current
+        };
+        
+        for block in &mut self.blocks {
+            block.terminator.map_targets(destination);
+            block.terminator = match block.terminator {
+                Terminator::JumpIfZero(taken, not_taken) |
+                Terminator::JumpIfNegative(taken, not_taken) if taken == not_taken => Terminator::FallThrough(taken),
+                ref terminator => terminator.clone(),
+            };
+        }
+    }
+    
+    /// Removes the blocks that can't be reached from the start of the program.
+    pub fn remove_unreachable(&mut self) {
+        let mut reachable = HashSet::new();
+        let mut to_visit = vec![0];
+        while let Some(id) = to_visit.pop() {
+            if id < self.blocks.len() && reachable.insert(id) {
+                to_visit.extend(self.blocks[id].terminator.successors());
+            }
+        }
+        
+        let mut ids = HashMap::new();
+        let mut blocks = Vec::new();
+        for (id, block) in self.blocks.drain(..).enumerate() {
+            if reachable.contains(&id) {
+                ids.insert(id, blocks.len());
+                blocks.push(block);
+            }
+        }
+        for block in &mut blocks {
+            block.terminator.map_targets(|target| ids[&target]);
+        }
+        self.blocks = blocks;
+    }
+    
+    /// Removes the labels that no jump goes to.
+    pub fn remove_unused_labels(&mut self) {
+        let mut targets = HashSet::new();
+        for (id, block) in self.blocks.iter().enumerate() {
+            targets.extend(match block.terminator {
+                Terminator::FallThrough(target) if target == id + 1 => vec![],
+                Terminator::JumpIfZero(taken, not_taken) |
+                Terminator::JumpIfNegative(taken, not_taken) if not_taken == id + 1 => vec![taken],
+                ref terminator => terminator.successors(),
+            });
+        }
+        
+        //jumps only ever use the first label of a block
+        for (id, block) in self.blocks.iter_mut().enumerate() {
+            block.labels.truncate(if targets.contains(&id) { 1 } else { 0 });
+        }
+    }
+    
+    /// Writes the blocks out in order. Jumps are added wherever a block doesn't go on to the one
+    /// after it, and labels that are needed for them but missing are made with `labels`.
+    pub fn to_commands(&self, labels: &mut LabelGenerator) -> Vec<Command> {
+        let last = self.blocks.len().saturating_sub(1);
+        let mut names: HashMap<BlockId, Label> = self.blocks.iter()
+            .enumerate()
+            .filter_map(|(id, block)| block.labels.first().map(|label| (id, label.clone())))
+            .collect();
+        //a halt that isn't at the end has to jump there
+        let exit = self.blocks.len();
+        //each block gets a construct of its own, so the descriptive names don't clash
+        let mut reference = |id: BlockId| names.entry(id)
+            .or_insert_with(|| {
+                let construct = labels.construct("block");
+                labels.label(&construct, if id == exit { "exit" } else { "start" })
+            })
+            .reference();
+        
+        let mut jumps = Vec::new();
+        for (id, block) in self.blocks.iter().enumerate() {
+            let falls_to = |target: BlockId| target == id + 1;
+            jumps.push(match &block.terminator {
+                Terminator::Jump(target) => vec![Command::Jump(reference(*target))],
+                Terminator::JumpIfZero(taken, not_taken) => {
+                    let mut out = vec![Command::JumpIfZero(reference(*taken))];
+                    if !falls_to(*not_taken) {
+                        out.push(Command::Jump(reference(*not_taken)));
+                    }
+                    out
+                }
+                Terminator::JumpIfNegative(taken, not_taken) => {
+                    let mut out = vec![Command::JumpIfNegative(reference(*taken))];
+                    if !falls_to(*not_taken) {
+                        out.push(Command::Jump(reference(*not_taken)));
+                    }
+                    out
+                }
+                Terminator::FallThrough(target) if !falls_to(*target) => vec![Command::Jump(reference(*target))],
+                Terminator::Halt if id != last => vec![Command::Jump(reference(exit))],
+                _ => vec![],
+            });
+        }
+        
+        let mut out = Vec::new();
+        for ((id, block), jumps) in self.blocks.iter().enumerate().zip(jumps) {
+            if block.labels.is_empty() {
+                out.extend(names.get(&id).cloned().map(Command::Label));
+            } else {
+                out.extend(block.labels.iter().cloned().map(Command::Label));
+            }
+            out.extend(block.commands.iter().cloned());
+            out.extend(jumps);
+        }
+        out.extend(names.remove(&exit).map(Command::Label));
+        
+        out
+    }
+}
@@ -25,6 +25,14 @@ pub enum Lexeme {
     Else,
     While,
     Break,
+    QuestionMark,
+    Colon,
+    Label,
+    Goto,
+    Asm,
+    LeftSquareBracket,
+    RightSquareBracket,
+    Identifier(String),
 }
 
 impl From<LexemeType> for Lexeme {
@@ -51,6 +59,14 @@ impl From<LexemeType> for Lexeme {
             LexemeType::Else => Lexeme::Else,
             LexemeType::While => Lexeme::While,
             LexemeType::Break => Lexeme::Break,
+            LexemeType::QuestionMark => Lexeme::QuestionMark,
+            LexemeType::Colon => Lexeme::Colon,
+            LexemeType::Label => Lexeme::Label,
+            LexemeType::Goto => Lexeme::Goto,
+            LexemeType::Asm => Lexeme::Asm,
+            LexemeType::LeftSquareBracket => Lexeme::LeftSquareBracket,
+            LexemeType::RightSquareBracket => Lexeme::RightSquareBracket,
+            LexemeType::Identifier => Lexeme::Identifier(String::new()),
         }
     }
 }
@@ -78,6 +94,14 @@ enum LexemeType {
     Else,
     While,
     Break,
+    QuestionMark,
+    Colon,
+    Label,
+    Goto,
+    Asm,
+    LeftSquareBracket,
+    RightSquareBracket,
+    Identifier,
 }
 
 impl From<Lexeme> for LexemeType {
@@ -104,34 +128,53 @@ impl From<Lexeme> for LexemeType {
             Lexeme::Else => LexemeType::Else,
             Lexeme::While => LexemeType::While,
             Lexeme::Break => LexemeType::Break,
+            Lexeme::QuestionMark => LexemeType::QuestionMark,
+            Lexeme::Colon => LexemeType::Colon,
+            Lexeme::Label => LexemeType::Label,
+            Lexeme::Goto => LexemeType::Goto,
+            Lexeme::Asm => LexemeType::Asm,
+            Lexeme::LeftSquareBracket => LexemeType::LeftSquareBracket,
+            Lexeme::RightSquareBracket => LexemeType::RightSquareBracket,
+            Lexeme::Identifier(_) => LexemeType::Identifier,
         }
     }
 }
 
-pub fn lex(source: &str) -> Result<Vec<Lexeme>, LexError> {
+/// Splits `source` into lexemes, and gives the line each of them is on, counting from 0
+pub fn lex(source: &str) -> Result<(Vec<Lexeme>, Vec<usize>), LexError> {
     lazy_static! {
         static ref LEXEMES: Vec<(Regex, LexemeType)> = {
             vec![
-                (Regex::new("input"), LexemeType::Input),
-                (Regex::new("output"), LexemeType::Output),
-                (Regex::new("\\("), LexemeType::LeftParentheses),
-                (Regex::new("\\)"), LexemeType::RightParentheses),
-                (Regex::new("\\+"), LexemeType::Plus),
-                (Regex::new("="), LexemeType::Equals),
-                (Regex::new("loop"), LexemeType::Loop),
-                (Regex::new("\\{"), LexemeType::LeftCurlyBracket),
-                (Regex::new("\\}"), LexemeType::RightCurlyBracket),
-                (Regex::new("\\*"), LexemeType::Star),
-                (Regex::new("\\d+"), LexemeType::Number),
-                (Regex::new(";"), LexemeType::Semicolon),
-                (Regex::new("if"), LexemeType::If),
-                (Regex::new("!"), LexemeType::ExclamationMark),
-                (Regex::new("-"), LexemeType::Minus),
-                (Regex::new("<"), LexemeType::LeftArrow),
-                (Regex::new(">"), LexemeType::RightArrow),
-                (Regex::new("else"), LexemeType::Else),
-                (Regex::new("while"), LexemeType::While),
-                (Regex::new("break"), LexemeType::Break),
+                (Regex::new("^input\\b"), LexemeType::Input),
+                (Regex::new("^output\\b"), LexemeType::Output),
+                (Regex::new("^\\("), LexemeType::LeftParentheses),
+                (Regex::new("^\\)"), LexemeType::RightParentheses),
+                (Regex::new("^\\+"), LexemeType::Plus),
+                (Regex::new("^="), LexemeType::Equals),
+                (Regex::new("^loop\\b"), LexemeType::Loop),
+                (Regex::new("^\\{"), LexemeType::LeftCurlyBracket),
+                (Regex::new("^\\}"), LexemeType::RightCurlyBracket),
+                (Regex::new("^\\*"), LexemeType::Star),
+                (Regex::new("^\\d+"), LexemeType::Number),
+                (Regex::new("^;"), LexemeType::Semicolon),
+                (Regex::new("^,"), LexemeType::Comma),
+                (Regex::new("^if\\b"), LexemeType::If),
+                (Regex::new("^!"), LexemeType::ExclamationMark),
+                (Regex::new("^-"), LexemeType::Minus),
+                (Regex::new("^<"), LexemeType::LeftArrow),
+                (Regex::new("^>"), LexemeType::RightArrow),
+                (Regex::new("^else\\b"), LexemeType::Else),
+                (Regex::new("^while\\b"), LexemeType::While),
+                (Regex::new("^break\\b"), LexemeType::Break),
+                (Regex::new("^\\?"), LexemeType::QuestionMark),
+                (Regex::new("^:"), LexemeType::Colon),
+                (Regex::new("^label\\b"), LexemeType::Label),
+                (Regex::new("^goto\\b"), LexemeType::Goto),
+                (Regex::new("^asm\\b"), LexemeType::Asm),
+                (Regex::new("^\\["), LexemeType::LeftSquareBracket),
+                (Regex::new("^\\]"), LexemeType::RightSquareBracket),
+                //Has to come after the keywords since it matches them as well
+                (Regex::new("^[A-Za-z_]\\w*"), LexemeType::Identifier),
             ].into_iter()
                 .map(|e| (e.0.unwrap(), e.1))
                 .collect()
@@ -139,24 +182,34 @@ pub fn lex(source: &str) -> Result<Vec<Lexeme>, LexError> {
     }
     
     let mut out = Vec::new();
+    let mut lines = Vec::new();
+    let mut line = 0;
     let mut copied = source.to_string();
     
     while copied.len() > 0 {
-        copied = copied.trim_start().to_string();
+        let trimmed = copied.trim_start();
+        line += copied[..copied.len() - trimmed.len()].matches('\n').count();
+        copied = trimmed.to_string();
+        if copied.is_empty() {
+            break;
+        }
+        lines.push(line);
         let mut to_remove = None;
         for lexeme_pattern in &*LEXEMES {
             if let Some(t) = lexeme_pattern.0.find(&copied) {
-                if t.start() == 0 {
-                    to_remove = Some(t.as_str().to_string());
-                    match &lexeme_pattern.1 {
-                        LexemeType::Number => {
-                            out.push(Lexeme::Number(t.as_str().parse::<u8>().unwrap()));
-                        }
-                        a => {
-                            out.push(a.clone().into());
-                        }
+                to_remove = Some(t.as_str().to_string());
+                match &lexeme_pattern.1 {
+                    LexemeType::Number => {
+                        out.push(Lexeme::Number(t.as_str().parse::<u8>().unwrap()));
+                    }
+                    LexemeType::Identifier => {
+                        out.push(Lexeme::Identifier(t.as_str().to_string()));
+                    }
+                    a => {
+                        out.push(a.clone().into());
                     }
                 }
+                break;
             }
         }
         
@@ -165,12 +218,13 @@ pub fn lex(source: &str) -> Result<Vec<Lexeme>, LexError> {
                 copied.replace_range(0..t.len(), "");
             }
             None => {
-                return Err(LexError::InvalidTokenError(copied));
+                let text = copied.lines().next().unwrap_or_default().to_string();
+                return Err(LexError::InvalidTokenError { text, line });
             }
         }
     }
     
-    Ok(out)
+    Ok((out, lines))
 }
 
 pub struct LexemePattern {
@@ -226,6 +280,14 @@ impl LexemePattern {
                         ranges.push(range);
                         ptr += num;
                     }
+                    Quantity::Any => {
+                        let start = ptr;
+                        while ptr < lexemes.len() && character.0.matches(&lexemes[ptr], depth_table[ptr]) {
+                            ptr += 1;
+                        }
+                        
+                        ranges.push(start..ptr);
+                    }
                     Quantity::Infinite => {
                         let start = ptr;
                         if ptr >= lexemes.len() {
@@ -269,10 +331,11 @@ impl LexemeMatcher {
     }
     
     fn matches(&self, lexeme: &Lexeme, depth: i32) -> bool {
-        self.0(lexeme) && match (self.1).0 {
-            DepthCriteria::Zero => depth == 0,
-            DepthCriteria::OneOrMore => depth >= 1,
-            DepthCriteria::Any => true,
+        match (self.1).0 {
+            DepthCriteria::Zero => depth == 0 && self.0(lexeme),
+            DepthCriteria::OneOrMore => depth >= 1 && self.0(lexeme),
+            DepthCriteria::Any => self.0(lexeme),
+            DepthCriteria::TopLevel => depth > 0 || self.0(lexeme),
         }
     }
 }
@@ -281,6 +344,8 @@ impl LexemeMatcher {
 pub enum Quantity {
     Finite(usize),
     Infinite,
+    /// Like `Infinite`, but also matches zero lexemes.
+    Any,
 }
 
 #[derive(Debug)]
@@ -288,6 +353,8 @@ pub enum DepthCriteria {
     Zero,
     OneOrMore,
     Any,
+    /// The lexeme has to pass the check only when it isn't inside brackets. Anything nested matches.
+    TopLevel,
 }
 
 #[derive(Debug)]
@@ -316,5 +383,6 @@ fn create_depth_table(tokens: &[Lexeme], deeper: Lexeme, shallower: Lexeme) -> V
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum LexError {
-    InvalidTokenError(String),
+    /// `text` is the rest of the line from where no lexeme matched
+    InvalidTokenError { text: String, line: usize },
 }
\ No newline at end of file
@@ -0,0 +1,101 @@
+//! Works out which tiles might still be read before they are written again. A `COPYTO` to a tile
+//! that isn't read again does nothing, since only what goes to the outbox counts. Tiles read
+//! through a pointer, like `COPYFROM [3]`, could be any tile, so after one of those every tile
+//! counts as read.
+
+use std::collections::HashSet;
+use crate::compiler::{Command, Reference};
+use crate::ir::Cfg;
+
+/// For every tile, whether its value might still be read
+type Live = [bool; 256];
+
+/// Removes the `COPYTO`s to tiles that are written again or never looked at after them.
+pub fn remove_dead_stores(cfg: &mut Cfg) {
+    let ends = live_at_end(cfg);
+    for (block, end) in cfg.blocks.iter_mut().zip(ends) {
+        let mut live = end;
+        let mut keep = vec![true; block.commands.len()];
+        for (i, command) in block.commands.iter().enumerate().rev() {
+            if let Command::CopyTo(Reference::Pointer(tile)) = command {
+                keep[i] = live[*tile as usize];
+            }
+            before(&mut live, command);
+        }
+        
+        let mut keep = keep.into_iter();
+        block.commands.retain(|_| keep.next().unwrap_or(true));
+    }
+}
+
+/// The tiles that are read anywhere in the program, or `None` if it reads through a pointer, in
+/// which case it could be any of them.
+pub fn read_tiles(cfg: &Cfg) -> Option<HashSet<u8>> {
+    let mut tiles = HashSet::new();
+    for command in cfg.blocks.iter().flat_map(|e| &e.commands) {
+        match command {
+            Command::CopyFrom(tile) | Command::Add(tile) | Command::Subtract(tile) |
+            Command::Increment(tile) | Command::Decrement(tile) => match tile {
+                Reference::Pointer(tile) => {
+                    tiles.insert(*tile);
+                }
+                Reference::PointerPointer(_) => return None,
+                Reference::Number(_) => {}
+            },
+            Command::CopyTo(Reference::PointerPointer(tile)) => {
+                tiles.insert(*tile);
+            }
+            _ => {}
+        }
+    }
+    
+    Some(tiles)
+}
+
+/// Which tiles might be read after the end of each block. Nothing is read after the program
+/// stops.
+fn live_at_end(cfg: &Cfg) -> Vec<Live> {
+    let mut starts = vec![[false; 256]; cfg.blocks.len()];
+    let end = |starts: &[Live], id: usize| {
+        let mut live = [false; 256];
+        for next in cfg.blocks[id].terminator.successors() {
+            for (tile, read) in starts[next].iter().enumerate() {
+                live[tile] |= read;
+            }
+        }
+        live
+    };
+    
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for id in (0..cfg.blocks.len()).rev() {
+            let mut live = end(&starts, id);
+            for command in cfg.blocks[id].commands.iter().rev() {
+                before(&mut live, command);
+            }
+            if live != starts[id] {
+                starts[id] = live;
+                changed = true;
+            }
+        }
+    }
+    
+    (0..cfg.blocks.len()).map(|id| end(&starts, id)).collect()
+}
+
+/// Turns what is live after `command` into what is live before it
+fn before(live: &mut Live, command: &Command) {
+    match command {
+        Command::CopyTo(Reference::Pointer(tile)) => live[*tile as usize] = false,
+        //the tile written to through the pointer can't be known, so nothing is overwritten for sure
+        Command::CopyTo(Reference::PointerPointer(tile)) => live[*tile as usize] = true,
+        Command::CopyFrom(tile) | Command::Add(tile) | Command::Subtract(tile) |
+        Command::Increment(tile) | Command::Decrement(tile) => match tile {
+            Reference::Pointer(tile) => live[*tile as usize] = true,
+            Reference::PointerPointer(_) => *live = [true; 256],
+            Reference::Number(_) => {}
+        },
+        _ => {}
+    }
+}
\ No newline at end of file
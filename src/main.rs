@@ -1,14 +1,25 @@
 mod preprocessor;
 mod lexer;
 mod parser;
+#[macro_use]
 mod parser2;
+mod builtins;
 mod compiler;
+mod ir;
+mod accumulator;
+mod liveness;
+mod interpreter;
+mod superoptimizer;
+mod peephole;
 
 use std::fs::File;
 use std::io::{Read, Write};
-use crate::preprocessor::{parse_macros, trim, find_add_square};
-use crate::lexer::{lex, Lexeme};
+use std::path::{Path, PathBuf};
+use crate::preprocessor::{parse_macros, find_add_square, find_constants, find_scratch, Location};
+use crate::lexer::{lex, Lexeme, LexError};
 use crate::parser::parse_tokens;
+use crate::compiler::{Memory, LabelGenerator, LabelStyle, Goal, Reference, check_labels};
+use crate::ir::Cfg;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum CompileError {
@@ -16,37 +27,179 @@ pub enum CompileError {
     InvalidCommandError(Vec<Lexeme>),
     InvalidToAssignTo(Vec<Lexeme>),
     NumberInsertionError,
+    MissingConstant(i16),
+    /// `tile` was declared with `#constant` to hold `value`, so it can't be written to
+    WriteToConstant { tile: Reference, value: i16 },
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    JumpIntoBlock(String),
+    UnknownFunction(String),
+    InvalidArguments(String),
+    /// Every scratch tile is holding a temporary, the number is how many there are
+    ScratchExhausted(usize),
+    /// `definition` and `call` are where the macro was defined and the line that used it
+    MacroError { message: String, definition: Location, call: Location },
+    DirectiveError { message: String, location: Location },
+    /// Text that isn't any lexeme, up to the end of the line
+    InvalidToken(String),
+    /// `error` is about the code at `location`
+    Located { error: Box<CompileError>, location: Location },
+}
+
+impl CompileError {
+    /// Says that the error is about the code at `location`, unless it already says where it is
+    pub fn at(self, location: &Location) -> Self {
+        match self {
+            CompileError::Located { .. } | CompileError::DirectiveError { .. } | CompileError::MacroError { .. } => self,
+            error => CompileError::Located { error: Box::new(error), location: location.clone() },
+        }
+    }
+}
+
+/// The command line arguments:
+/// `[input] [-o output] [-I path]... [-D name[=value]]... [--keep-comments] [--readable-labels]`
+/// `[-O0|-O1] [--optimize size|speed] [--superoptimize length]`
+struct Options {
+    input: String,
+    output: String,
+    /// The folders to look for included files in
+    include_paths: Vec<PathBuf>,
+    /// Macros defined with `-D`, those without a value are defined as 1
+    defines: Vec<(String, String)>,
+    /// Whether comments on their own line become `COMMENT` markers
+    keep_comments: bool,
+    label_style: LabelStyle,
+    /// Whether the optimisations run, `-O0` turns them off
+    optimize: bool,
+    goal: Goal,
+    /// The longest run of commands the superoptimiser tries, it only runs when this is given
+    superoptimize: Option<usize>,
+}
+
+impl Options {
+    fn parse(mut arguments: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            input: String::from("in.txt"),
+            output: String::from("out.txt"),
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+            keep_comments: false,
+            label_style: LabelStyle::Compact,
+            optimize: true,
+            goal: Goal::Size,
+            superoptimize: None,
+        };
+        
+        while let Some(argument) = arguments.next() {
+            if argument == "--readable-labels" {
+                options.label_style = LabelStyle::Descriptive;
+            } else if argument == "--keep-comments" {
+                options.keep_comments = true;
+            } else if argument == "-O0" {
+                options.optimize = false;
+            } else if argument == "-O1" {
+                options.optimize = true;
+            } else if argument == "--optimize" {
+                options.goal = match arguments.next().as_deref() {
+                    Some("size") => Goal::Size,
+                    Some("speed") => Goal::Speed,
+                    _ => return Err(String::from("--optimize needs size or speed")),
+                };
+            } else if argument == "--superoptimize" {
+                options.superoptimize = Some(arguments.next().and_then(|e| e.parse().ok())
+                    .ok_or("--superoptimize needs the length of the longest run to try, like 3")?);
+            } else if argument == "-o" {
+                options.output = arguments.next().ok_or("-o needs a file name")?;
+            } else if let Some(path) = argument.strip_prefix("-I") {
+                options.include_paths.push(PathBuf::from(match path {
+                    "" => arguments.next().ok_or("-I needs a folder")?,
+                    path => path.to_string(),
+                }));
+            } else if let Some(define) = argument.strip_prefix("-D") {
+                let define = match define {
+                    "" => arguments.next().ok_or("-D needs a macro name")?,
+                    define => define.to_string(),
+                };
+                options.defines.push(match define.split_once('=') {
+                    Some((name, value)) => (name.to_string(), value.to_string()),
+                    None => (define, String::from("1")),
+                });
+            } else if argument.starts_with('-') {
+                return Err(format!("unknown option {}", argument));
+            } else {
+                options.input = argument;
+            }
+        }
+        
+        Ok(options)
+    }
 }
 
 fn main() {
+    let options = Options::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    
     let mut read = String::new();
-    let mut in_file = File::open("in.txt").unwrap(); //TODO: make this safe
+    let mut in_file = File::open(&options.input).unwrap(); //TODO: make this safe
 
     in_file.read_to_string(&mut read).unwrap();
     
-    read = parse_macros(read);
-    let (mut read, add_square) = find_add_square(read);
+    let (processed, variables, locations) = parse_macros(read, Path::new(&options.input), &options.include_paths,
+                                                         &options.defines, options.keep_comments).unwrap();
+    read = processed;
+    let (read, add_square) = find_add_square(read);
+    let (read, mut scratch) = find_scratch(read, &locations).unwrap();
+    let (read, constants) = find_constants(read, &locations).unwrap();
+    if scratch.is_empty() {
+        scratch.push(add_square);
+    }
+    let mut memory = Memory::new(scratch, constants, options.goal);
     println!("parsed macros");
-    read = trim(read);
-    println!("trimmed");
     
-    let lexed = lex(&read).unwrap();
+    let (lexed, lines) = lex(&read).map_err(|LexError::InvalidTokenError { text, line }| CompileError::Located {
+        error: Box::new(CompileError::InvalidToken(text)),
+        location: locations[line].clone(),
+    }).unwrap();
     println!("lexed {:?}", lexed);
-    let parsed = parse_tokens(lexed).unwrap();
+    let lexed_locations = lines.into_iter().map(|e| locations[e].clone()).collect();
+    let mut parsed = parse_tokens(lexed, lexed_locations).unwrap();
     println!("parsed");
+    if options.optimize {
+        parser2::simplify_block(&mut parsed.root, &memory);
+    }
 
     println!("{:?}", parsed);
     
     let mut output = String::from("-- HUMAN RESOURCE MACHINE PROGRAM --\n\n");
 
-    let mut label_counter = 0;
+    let mut labels = LabelGenerator::new(options.label_style);
+    labels.reserve(parsed.labels.iter().cloned());
+    let mut commands = Vec::new();
     for command in &parsed.root {
-        output.extend(command.to_command(&mut label_counter, add_square, None).unwrap()
-            .into_iter()
-            .map(|e| e.to_string()));
+        commands.extend(command.to_command(&mut labels, &mut memory, None).unwrap());
+    }
+    check_labels(&commands).unwrap();
+    let mut cfg = Cfg::from_commands(commands).unwrap();
+    if let Some(read_tiles) = liveness::read_tiles(&cfg) {
+        for variable in variables.iter().filter(|e| !read_tiles.contains(&e.tile)) {
+            eprintln!("warning: {}: variable `{}` (tile {}) is never read", variable.location, variable.name, variable.tile);
+        }
+    }
+    if options.optimize {
+        cfg.optimize(options.goal);
+    }
+    if let Some(longest) = options.superoptimize {
+        superoptimizer::optimize(&mut cfg, longest);
+    }
+    let mut commands = cfg.to_commands(&mut labels);
+    if options.optimize {
+        commands = peephole::optimize(commands, options.goal);
     }
+    output.extend(commands.into_iter().map(|e| e.to_string()));
 
-    let mut out_file = File::create("out.txt").unwrap();
+    let mut out_file = File::create(&options.output).unwrap();
 
     out_file.write(output.as_bytes()).unwrap();
 }
\ No newline at end of file
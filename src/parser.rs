@@ -1,19 +1,115 @@
 use crate::lexer::{Lexeme, LexemePattern, LexemeMatcher, Quantity, DepthType, DepthCriteria};
-use crate::{CompileError, parser2};
-use crate::compiler::Command;
+use crate::preprocessor::Location;
+use crate::{CompileError, parser2, builtins};
+use crate::compiler::{Command, LabelGenerator, LabelStyle, Memory, Reference};
 use std::ops::Range;
+use std::collections::{HashMap, HashSet};
 use lazy_static::lazy_static;
 use matches::matches;
 use crate::parser2::*;
 
-pub fn parse_tokens(tokens: Vec<Lexeme>) -> Result<AST, CompileError> {
-    AST::new(parse_tokenized_expression(tokens)?)
+/// Parses the lexemes of the program. `locations` says where each lexeme is in the source, so
+/// the errors can say where they are.
+pub fn parse_tokens(tokens: Vec<Lexeme>, locations: Vec<Location>) -> Result<AST, CompileError> {
+    let (lexemes, locations) = desugar_else_if(tokens, locations);
+    let tokens = Tokens { lexemes, locations };
+    let expressions = parse_tokenized_expression(tokens.clone())?;
+    AST::new(expressions, &tokens)
 }
 
-type Syntax = (LexemePattern, Box<dyn Fn(Vec<Range<usize>>, &[Lexeme]) ->
+/// Rewrites every `else if (...) {...}` into `else { if (...) {...} }` so that the if/else
+/// syntax only has to deal with plain else blocks. The chain is flattened again by `IfChain`.
+/// The brackets that are added get the location of the lexeme they are put in front of.
+fn desugar_else_if(mut tokens: Vec<Lexeme>, mut locations: Vec<Location>) -> (Vec<Lexeme>, Vec<Location>) {
+    let mut i = 0;
+    while i + 1 < tokens.len() {
+        if tokens[i] == Lexeme::Else && tokens[i + 1] == Lexeme::If {
+            let end = end_of_if(&tokens, i + 1);
+            tokens.insert(end, Lexeme::RightCurlyBracket);
+            locations.insert(end, locations[end.min(locations.len() - 1)].clone());
+            tokens.insert(i + 1, Lexeme::LeftCurlyBracket);
+            locations.insert(i + 1, locations[i + 1].clone());
+        }
+        i += 1;
+    }
+    
+    (tokens, locations)
+}
+
+/// Finds the index just past the if statement starting at `start`, including any else arms.
+fn end_of_if(tokens: &[Lexeme], start: usize) -> usize {
+    let mut ptr = skip_group(tokens, start + 1, &Lexeme::LeftParentheses, &Lexeme::RightParentheses);
+    ptr = skip_group(tokens, ptr, &Lexeme::LeftCurlyBracket, &Lexeme::RightCurlyBracket);
+    
+    match (tokens.get(ptr), tokens.get(ptr + 1)) {
+        (Some(Lexeme::Else), Some(Lexeme::If)) => end_of_if(tokens, ptr + 1),
+        (Some(Lexeme::Else), _) =>
+            skip_group(tokens, ptr + 1, &Lexeme::LeftCurlyBracket, &Lexeme::RightCurlyBracket),
+        _ => ptr,
+    }
+}
+
+/// Skips a balanced group that opens at `start`. Returns `start` untouched if there is no group there.
+fn skip_group(tokens: &[Lexeme], start: usize, open: &Lexeme, close: &Lexeme) -> usize {
+    if tokens.get(start) != Some(open) {
+        return start;
+    }
+    
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        if token == open {
+            depth += 1;
+        } else if token == close {
+            depth -= 1;
+            if depth == 0 {
+                return i + 1;
+            }
+        }
+    }
+    
+    tokens.len()
+}
+
+/// Lexemes and where each of them is in the source. It can be used as a slice of the lexemes.
+#[derive(Debug, Clone)]
+struct Tokens {
+    lexemes: Vec<Lexeme>,
+    locations: Vec<Location>,
+}
+
+impl Tokens {
+    fn slice(&self, range: Range<usize>) -> Self {
+        Self { lexemes: self.lexemes[range.clone()].to_vec(), locations: self.locations[range].to_vec() }
+    }
+    
+    fn splice(&mut self, range: Range<usize>) -> Vec<Lexeme> {
+        self.locations.splice(range.clone(), vec![]);
+        self.lexemes.splice(range, vec![]).collect()
+    }
+    
+    /// Puts `error` at the lexeme at `position`, if it doesn't say where it is already
+    fn locate(&self, error: CompileError, position: usize) -> CompileError {
+        match self.locations.get(position).or_else(|| self.locations.last()) {
+            Some(location) => error.at(location),
+            None => error,
+        }
+    }
+}
+
+impl std::ops::Deref for Tokens {
+    type Target = [Lexeme];
+    
+    fn deref(&self) -> &[Lexeme] {
+        &self.lexemes
+    }
+}
+
+type Syntax = (LexemePattern, Box<dyn Fn(Vec<Range<usize>>, &Tokens) ->
     Result<AnyExpressionType, CompileError> + Send + Sync>);
 
-fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionType>, CompileError> {
+/// Parses `tokens` into expressions. The ones from the first class of syntax are statements, which
+/// are put in a `Statement` so that they know where they are.
+fn parse_tokenized_expression(tokens: Tokens) -> Result<Vec<AnyExpressionType>, CompileError> {
     lazy_static! {
         static ref EXPRESSIONS: Vec<Vec<Syntax>> = vec![
             vec![
@@ -25,7 +121,7 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                         Some((DepthCriteria::OneOrMore, DepthType::CurlyBrackets))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightCurlyBracket)), None), Quantity::Finite(1)),
                 ]), Box::new(|t, tokens| {
-                    let inner = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?.into_iter()
+                    let inner = parse_range(tokens, t[2].clone())?.into_iter()
                         .map(|e| e.expression().unwrap())
                         .collect();
                     
@@ -43,14 +139,14 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                         Some((DepthCriteria::OneOrMore, DepthType::CurlyBrackets))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightCurlyBracket)), None), Quantity::Finite(1)),
                 ]), Box::new(|t, tokens| {
-                    let mut exp = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
-                    let commands = parse_tokenized_expression(tokens[t[5].clone()].to_vec())?.into_iter()
+                    let mut exp = parse_range(tokens, t[2].clone())?;
+                    let commands = parse_range(tokens, t[5].clone())?.into_iter()
                         .map(|e| e.expression().unwrap())
                         .collect();
                     assert_eq!(exp.len(), 1);
                     
                     Ok((Box::new(While::new(
-                        exp.remove(0).logical().unwrap(), commands)) as Box<dyn Expression>).into())
+                        exp.remove(0).into_logical().unwrap(), commands)) as Box<dyn Expression>).into())
                 })),
                 //If Else
                 (LexemePattern::new(vec![
@@ -69,17 +165,16 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                         Some((DepthCriteria::OneOrMore, DepthType::CurlyBrackets))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightCurlyBracket)), None), Quantity::Finite(1)),
                 ]), Box::new(|t, tokens| {
-                    let mut exp = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
-                    let commands1 = parse_tokenized_expression(tokens[t[5].clone()].to_vec())?.into_iter()
+                    let mut exp = parse_range(tokens, t[2].clone())?;
+                    let commands1 = parse_range(tokens, t[5].clone())?.into_iter()
                         .map(|e| e.expression().unwrap())
                         .collect();
-                    let commands2 = parse_tokenized_expression(tokens[t[9].clone()].to_vec())?.into_iter()
+                    let commands2 = parse_range(tokens, t[9].clone())?.into_iter()
                         .map(|e| e.expression().unwrap())
                         .collect();
                     assert_eq!(exp.len(), 1);
                     
-                    Ok((Box::new(IfElse::new(
-                        exp.remove(0).logical().unwrap(), commands1, commands2)) as Box<dyn Expression>).into())
+                    Ok(IfChain::flatten(exp.remove(0).into_logical().unwrap(), commands1, commands2).into())
                 })),
                 //If
                 (LexemePattern::new(vec![
@@ -93,14 +188,37 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                         Some((DepthCriteria::OneOrMore, DepthType::CurlyBrackets))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightCurlyBracket)), None), Quantity::Finite(1)),
                 ]), Box::new(|t, tokens| {
-                    let mut exp = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
-                    let commands = parse_tokenized_expression(tokens[t[5].clone()].to_vec())?.into_iter()
+                    let mut exp = parse_range(tokens, t[2].clone())?;
+                    let commands = parse_range(tokens, t[5].clone())?.into_iter()
                         .map(|e| e.expression().unwrap())
                         .collect();
                     assert_eq!(exp.len(), 1);
                     
                     return Ok((Box::new(If::new(
-                        exp.remove(0).logical().unwrap(), commands)) as Box<dyn Expression>).into());
+                        exp.remove(0).into_logical().unwrap(), commands)) as Box<dyn Expression>).into());
+                })),
+                //Asm
+                (LexemePattern::new(vec![
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Asm)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::LeftCurlyBracket)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::OneOrMore, DepthType::CurlyBrackets))), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightCurlyBracket)), None), Quantity::Finite(1)),
+                ]), Box::new(|t, tokens| {
+                    let lines = parse_asm(&tokens[t[2].clone()])?;
+                    Ok((Box::new(Asm::new(lines)?) as Box<dyn Expression>).into())
+                })),
+                //Label
+                (LexemePattern::new(vec![
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Label)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Identifier(_))), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Colon)), None), Quantity::Finite(1)),
+                ]), Box::new(|t, tokens| {
+                    match &tokens[t[1].start] {
+                        Lexeme::Identifier(name) =>
+                            Ok((Box::new(UserLabel::new(name.clone())) as Box<dyn Expression>).into()),
+                        _ => Err(CompileError::InvalidCommandError(tokens.to_vec())),
+                    }
                 })),
                 //Non block expressions
                 (LexemePattern::new(vec![
@@ -108,141 +226,217 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                         Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Semicolon)), None), Quantity::Finite(1)),
                 ]), Box::new(|t, tokens| {
-                    let mut command = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
+                    let mut command = parse_range(tokens, t[0].clone())?;
                     assert_eq!(command.len(), 1);
                     Ok(command.remove(0))
                 })),
             ],
+            vec![
+                //Assign to double deref
+                (LexemePattern::new(vec![
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Star)), None), Quantity::Finite(2)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Number(_))), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Equals)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Equals)),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Any),
+                ]), Box::new(|t, tokens| {
+                    let mut left = parse_range(tokens, 0..3)?;
+                    let mut right = parse_range(tokens, 4..tokens.len())?;
+                    assert_eq!(left.len(), 1);
+                    assert_eq!(right.len(), 1);
+                    
+                    Ok(AnyExpressionType::new(Some(Box::new(Assign::new(
+                        left.remove(0).value().unwrap(), right.remove(0).into_expression().unwrap()))), None, None))
+                })),
+                //Assign to single deref
+                (LexemePattern::new(vec![
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Star)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Number(_))), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Equals)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Equals)),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Any),
+                ]), Box::new(|t, tokens| {
+                    let mut left = parse_range(tokens, 0..2)?;
+                    let mut right = parse_range(tokens, 3..tokens.len())?;
+                    assert_eq!(left.len(), 1);
+                    assert_eq!(right.len(), 1);
+                    
+                    Ok(AnyExpressionType::new(Some(Box::new(Assign::new(
+                        left.remove(0).value().unwrap(), right.remove(0).into_expression().unwrap()))), None, None))
+                })),
+            ],
+            vec![
+                //Conditional
+                (LexemePattern::new(vec![
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::QuestionMark)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::QuestionMark)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Colon)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Colon)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
+                ]), Box::new(|t, tokens| {
+                    let mut condition = parse_range(tokens, t[0].clone())?;
+                    let mut if_true = parse_range(tokens, t[2].clone())?;
+                    let mut if_false = parse_range(tokens, t[4].clone())?;
+                    assert_eq!(condition.len(), 1);
+                    assert_eq!(if_true.len(), 1);
+                    assert_eq!(if_false.len(), 1);
+                    
+                    Ok((Box::new(Conditional::new(
+                        condition.remove(0).into_logical().unwrap(),
+                        if_true.remove(0).into_expression().unwrap(),
+                        if_false.remove(0).into_expression().unwrap())) as Box<dyn Expression>).into())
+                })),
+            ],
             vec![
                 //Not equals
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::ExclamationMark)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::ExclamationMark)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::ExclamationMark)), None), Quantity::Finite(1)),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Equals)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                 ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[t[3].clone()].to_vec())?;
+                    let mut left = parse_range(tokens, t[0].clone())?;
+                    let mut right = parse_range(tokens, t[3].clone())?;
                     assert_eq!(left.len(), 1);
                     assert_eq!(right.len(), 1);
                     
-                    if right[0] == (Box::new(Number::new(0)) as Box<dyn Value>).into() {
+                    if is_zero(&right[0]) {
                         return Ok((Box::new(
-                            NotZero::new(left.remove(0).expression().unwrap())) as Box<dyn Logical>).into());
-                    } else if left[0] == (Box::new(Number::new(0)) as Box<dyn Value>).into() {
+                            NotZero::new(left.remove(0).into_expression().unwrap())) as Box<dyn Logical>).into());
+                    } else if is_zero(&left[0]) {
                         return Ok((Box::new(
-                            NotZero::new(right.remove(0).expression().unwrap())) as Box<dyn Logical>).into());
+                            NotZero::new(right.remove(0).into_expression().unwrap())) as Box<dyn Logical>).into());
                     }
                     
                     Ok((Box::new(NotZero::new(Box::new(Subtract::new(
-                        left.remove(0).expression().unwrap(),
-                        right.remove(0).expression().unwrap())))) as Box<dyn Logical>).into())
+                        left.remove(0).into_expression().unwrap(),
+                        right.remove(0).into_expression().unwrap())))) as Box<dyn Logical>).into())
                 })),
                 //Equals
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Equals)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Equals)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Equals)), None), Quantity::Finite(2)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                 ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
+                    let mut left = parse_range(tokens, t[0].clone())?;
+                    let mut right = parse_range(tokens, t[2].clone())?;
                     assert_eq!(left.len(), 1);
                     assert_eq!(right.len(), 1);
                     
-                    if right[0] == (Box::new(Number::new(0)) as Box<dyn Value>).into() {
+                    if is_zero(&right[0]) {
                         return Ok((Box::new(
-                            IsZero::new(left.remove(0).expression().unwrap())) as Box<dyn Logical>).into())
-                    } else if left[0] == (Box::new(Number::new(0)) as Box<dyn Value>).into() {
+                            IsZero::new(left.remove(0).into_expression().unwrap())) as Box<dyn Logical>).into())
+                    } else if is_zero(&left[0]) {
                         return Ok((Box::new(
-                            IsZero::new(right.remove(0).expression().unwrap())) as Box<dyn Logical>).into())
+                            IsZero::new(right.remove(0).into_expression().unwrap())) as Box<dyn Logical>).into())
                     }
                     Ok((Box::new(IsZero::new(Box::new(Subtract::new(
-                        left.remove(0).expression().unwrap(),
-                        right.remove(0).expression().unwrap())))) as Box<dyn Logical>).into())
+                        left.remove(0).into_expression().unwrap(),
+                        right.remove(0).into_expression().unwrap())))) as Box<dyn Logical>).into())
                 })),
                 //Less than or equal
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::LeftArrow)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::LeftArrow)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::LeftArrow)), None), Quantity::Finite(1)),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Equals)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                 ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[t[3].clone()].to_vec())?;
+                    let mut left = parse_range(tokens, t[0].clone())?;
+                    let mut right = parse_range(tokens, t[3].clone())?;
                     assert_eq!(left.len(), 1);
                     assert_eq!(right.len(), 1);
                     
-                    if right[0] == (Box::new(Number::new(0)) as Box<dyn Value>).into() {
+                    if is_zero(&right[0]) {
                         return Ok((Box::new(
-                            LessOrEqualToZero::new(left.remove(0).expression().unwrap())) as Box<dyn Logical>).into())
+                            LessOrEqualToZero::new(left.remove(0).into_expression().unwrap())) as Box<dyn Logical>).into())
                     }
                     Ok(AnyExpressionType::new(None, None, Some(Box::new(LessOrEqualToZero::new(Box::new(Subtract::new(
-                        left.remove(0).expression().unwrap(), right.remove(0).expression().unwrap())))))))
+                        left.remove(0).into_expression().unwrap(), right.remove(0).into_expression().unwrap())))))))
                 })),
                 //Greater or equal than
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::RightArrow)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::RightArrow)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightArrow)), None), Quantity::Finite(1)),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Equals)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                 ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[t[3].clone()].to_vec())?;
+                    let mut left = parse_range(tokens, t[0].clone())?;
+                    let mut right = parse_range(tokens, t[3].clone())?;
                     assert_eq!(left.len(), 1);
                     assert_eq!(right.len(), 1);
                     
-                    if right[0] == (Box::new(Number::new(0)) as Box<dyn Value>).into() {
+                    if is_zero(&right[0]) {
                         return Ok((Box::new(
-                            GreaterOrEqualToZero::new(left.remove(0).expression().unwrap())) as Box<dyn Logical>).into())
+                            GreaterOrEqualToZero::new(left.remove(0).into_expression().unwrap())) as Box<dyn Logical>).into())
                     }
                     Ok((Box::new(GreaterOrEqualToZero::new(Box::new(Subtract::new(
-                        left.remove(0).expression().unwrap(),
-                        right.remove(0).expression().unwrap())))) as Box<dyn Logical>).into())
+                        left.remove(0).into_expression().unwrap(),
+                        right.remove(0).into_expression().unwrap())))) as Box<dyn Logical>).into())
                 })),
                 //Less than
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::LeftArrow)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::LeftArrow)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::LeftArrow)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                 ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
+                    let mut left = parse_range(tokens, t[0].clone())?;
+                    let mut right = parse_range(tokens, t[2].clone())?;
                     assert_eq!(left.len(), 1);
                     assert_eq!(right.len(), 1);
                     
-                    if right[0] == (Box::new(Number::new(0)) as Box<dyn Value>).into() {
+                    if is_zero(&right[0]) {
                         return Ok((Box::new(
-                            LessThanZero::new(left.remove(0).expression().unwrap())) as Box<dyn Logical>).into());
+                            LessThanZero::new(left.remove(0).into_expression().unwrap())) as Box<dyn Logical>).into());
                     }
                     Ok(AnyExpressionType::new(None, None, Some(Box::new(LessThanZero::new(Box::new(Subtract::new(
-                        left.remove(0).expression().unwrap(), right.remove(0).expression().unwrap())))))))
+                        left.remove(0).into_expression().unwrap(), right.remove(0).into_expression().unwrap())))))))
                 })),
                 //Greater than
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::RightArrow)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::RightArrow)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightArrow)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                 ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
+                    let mut left = parse_range(tokens, t[0].clone())?;
+                    let mut right = parse_range(tokens, t[2].clone())?;
                     assert_eq!(left.len(), 1);
                     assert_eq!(right.len(), 1);
                     
-                    if right[0] == (Box::new(Number::new(0)) as Box<dyn Value>).into() {
+                    if is_zero(&right[0]) {
                         return Ok((Box::new(
-                            GreaterThanZero::new(left.remove(0).expression().unwrap())) as Box<dyn Logical>).into())
+                            GreaterThanZero::new(left.remove(0).into_expression().unwrap())) as Box<dyn Logical>).into())
                     }
                     Ok(AnyExpressionType::new(None, None, Some(Box::new(GreaterThanZero::new(Box::new(Subtract::new(
-                        left.remove(0).expression().unwrap(), right.remove(0).expression().unwrap())))))))
+                        left.remove(0).into_expression().unwrap(), right.remove(0).into_expression().unwrap())))))))
                 })),
             ],
             vec![
                 //Bump up
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Plus)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Plus)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Plus)), None), Quantity::Finite(2)),
                 ]), Box::new(|t, tokens| {
-                    let mut exp = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
+                    let mut exp = parse_range(tokens, t[0].clone())?;
                     assert_eq!(exp.len(), 1);
                     
                     Ok(AnyExpressionType::new(
@@ -251,10 +445,11 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                 })),
                 //Bump down
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Minus)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Minus)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Minus)), None), Quantity::Finite(2)),
                 ]), Box::new(|t, tokens| {
-                    let mut exp = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
+                    let mut exp = parse_range(tokens, t[0].clone())?;
                     assert_eq!(exp.len(), 1);
     
                     Ok(AnyExpressionType::new(
@@ -262,66 +457,38 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                         None, None))
                 })),
             ],
-            vec![
-                //Assign to double deref
-                (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Star)), None), Quantity::Finite(2)),
-                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Number(_))), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Equals)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
-                ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[0..3].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[4..].to_vec())?;
-                    assert_eq!(left.len(), 1);
-                    assert_eq!(right.len(), 1);
-                    
-                    Ok(AnyExpressionType::new(Some(Box::new(Assign::new(
-                        left.remove(0).value().unwrap(), right.remove(0).expression().unwrap()))), None, None))
-                })),
-                //Assign to single deref
-                (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Star)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Number(_))), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Equals)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
-                ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[0..2].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[3..].to_vec())?;
-                    assert_eq!(left.len(), 1);
-                    assert_eq!(right.len(), 1);
-                    
-                    Ok(AnyExpressionType::new(Some(Box::new(Assign::new(
-                        left.remove(0).value().unwrap(), right.remove(0).expression().unwrap()))), None, None))
-                })),
-            ],
             vec![
                 //Adding
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Plus)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Plus)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Plus)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                 ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
+                    let mut left = parse_range(tokens, t[0].clone())?;
+                    let mut right = parse_range(tokens, t[2].clone())?;
                     assert_eq!(left.len(), 1);
                     assert_eq!(right.len(), 1);
                     
                     Ok(AnyExpressionType::new(Some(Box::new(Add::new(
-                        left.remove(0).expression().unwrap(), right.remove(0).expression().unwrap()))), None, None))
+                        left.remove(0).into_expression().unwrap(), right.remove(0).into_expression().unwrap()))), None, None))
                 })),
                 //Subtracting
                 (LexemePattern::new(vec![
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Minus)), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::Minus)),
+                        Some((DepthCriteria::TopLevel, DepthType::Both))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Minus)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| true), None), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::Any, DepthType::Both))), Quantity::Infinite),
                 ]), Box::new(|t, tokens| {
-                    let mut left = parse_tokenized_expression(tokens[t[0].clone()].to_vec())?;
-                    let mut right = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
+                    let mut left = parse_range(tokens, t[0].clone())?;
+                    let mut right = parse_range(tokens, t[2].clone())?;
                     assert_eq!(left.len(), 1);
                     assert_eq!(right.len(), 1);
                     
                     Ok(AnyExpressionType::new(Some(Box::new(Subtract::new(
-                        left.remove(0).expression().unwrap(), right.remove(0).expression().unwrap()))), None, None))
+                        left.remove(0).into_expression().unwrap(), right.remove(0).into_expression().unwrap()))), None, None))
                 })),
             ],
             vec![
@@ -331,7 +498,7 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Star)), None), Quantity::Finite(1)),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Number(_))), None), Quantity::Finite(1)),
                 ]), Box::new(|t, tokens| {
-                    let mut res = parse_tokenized_expression(tokens[t[1].start..t[2].end].to_vec())?;
+                    let mut res = parse_range(tokens, t[1].start..t[2].end)?;
                     assert_eq!(res.len(), 1);
                     let val = Box::new(Deref::new(res.remove(0).value().unwrap()));
                     Ok(AnyExpressionType::new(Some(val.clone()),
@@ -343,7 +510,7 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Star)), None), Quantity::Finite(1)),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Number(_))), None), Quantity::Finite(1)),
                 ]), Box::new(|t, tokens| {
-                    let mut res = parse_tokenized_expression(tokens[t[1].clone()].to_vec())?;
+                    let mut res = parse_range(tokens, t[1].clone())?;
                     assert_eq!(res.len(), 1);
                     let val = Box::new(Deref::new(res.remove(0).value().unwrap()));
                     Ok(AnyExpressionType::new(Some(val.clone()),
@@ -356,13 +523,36 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                 (LexemePattern::new(vec![
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Output)), None), Quantity::Finite(1)),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::LeftParentheses)), None), Quantity::Finite(1)),
-                    (LexemeMatcher::new(Box::new(|l| !matches!(l, Lexeme::RightParentheses)),
+                    (LexemeMatcher::new(Box::new(|_| true),
                         Some((DepthCriteria::OneOrMore, DepthType::Parentheses))), Quantity::Infinite),
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightParentheses)), None), Quantity::Finite(1)),
                 ]), Box::new(|t, tokens| {
-                    let mut arg = parse_tokenized_expression(tokens[t[2].clone()].to_vec())?;
+                    let mut arg = parse_range(tokens, t[2].clone())?;
                     assert_eq!(arg.len(), 1);
-                    Ok(AnyExpressionType::new(Some(Box::new(Output::new(arg.remove(0).expression().unwrap()))), None, None))
+                    Ok(AnyExpressionType::new(Some(Box::new(Output::new(arg.remove(0).into_expression().unwrap()))), None, None))
+                })),
+            ],
+            vec![
+                //Built-in function call
+                (LexemePattern::new(vec![
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Identifier(_))), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::LeftParentheses)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::OneOrMore, DepthType::Parentheses))), Quantity::Any),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightParentheses)), None), Quantity::Finite(1)),
+                ]), Box::new(|t, tokens| {
+                    let name = match &tokens[t[0].start] {
+                        Lexeme::Identifier(name) => name,
+                        _ => return Err(CompileError::InvalidCommandError(tokens.to_vec())),
+                    };
+                    let mut arguments = Vec::new();
+                    for argument in split_arguments(&tokens[t[2].clone()]) {
+                        let mut parsed = parse_range(tokens, t[2].start + argument.start..t[2].start + argument.end)?;
+                        assert_eq!(parsed.len(), 1);
+                        arguments.push(parsed.remove(0));
+                    }
+                    
+                    Ok(builtins::call(name, arguments)?.into())
                 })),
             ],
             vec![
@@ -381,10 +571,13 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                     (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Number(_))), None), Quantity::Finite(1))
                 ]), Box::new(|t, tokens| {
                     if t[0].start == 0 {
-                        return Ok(AnyExpressionType::new(None, Some(Box::new(Number::new(match tokens[0] {
+                        let number = match tokens[0] {
                             Lexeme::Number(a) => a,
                             _ => panic!("nu blev något konstigt"),
-                        }))), None));
+                        };
+                        return Ok(AnyExpressionType::new(Some(Box::new(Constant::new(number as i16))),
+                                                         Some(Box::new(Number::new(number))),
+                                                         None));
                     }
                     Err(CompileError::InvalidCommandError(tokens.to_vec()))
                 })),
@@ -394,6 +587,30 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
                 ]), Box::new(|t, tokens| {
                     Ok(AnyExpressionType::new(Some(Box::new(Break {})), None, None))
                 })),
+                //Goto
+                (LexemePattern::new(vec![
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Goto)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::Identifier(_))), None), Quantity::Finite(1)),
+                ]), Box::new(|t, tokens| {
+                    match &tokens[t[1].start] {
+                        Lexeme::Identifier(name) =>
+                            Ok((Box::new(Goto::new(name.clone())) as Box<dyn Expression>).into()),
+                        _ => Err(CompileError::InvalidCommandError(tokens.to_vec())),
+                    }
+                })),
+            ],
+            vec![
+                //Parentheses
+                (LexemePattern::new(vec![
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::LeftParentheses)), None), Quantity::Finite(1)),
+                    (LexemeMatcher::new(Box::new(|_| true),
+                        Some((DepthCriteria::OneOrMore, DepthType::Parentheses))), Quantity::Infinite),
+                    (LexemeMatcher::new(Box::new(|l| matches!(l, Lexeme::RightParentheses)), None), Quantity::Finite(1)),
+                ]), Box::new(|t, tokens| {
+                    let mut inner = parse_range(tokens, t[1].clone())?;
+                    assert_eq!(inner.len(), 1);
+                    Ok(inner.remove(0))
+                })),
             ],
         ];
     }
@@ -404,7 +621,7 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
     for _ in 0..50 { //1000 was chosen arbitrarily to emulate a big number, might need to be refactored at a later point
         //println!("current tokens {:?}", current_tokens);
         let mut n = 0;
-        for expression_class in &*EXPRESSIONS {
+        for (class, expression_class) in EXPRESSIONS.iter().enumerate() {
             let mut earliest: Option<Vec<Range<usize>>> = None;
             let mut earliest_func = None;
             for expression in expression_class {
@@ -425,10 +642,18 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
             
             if earliest.is_some() {
                 let element = earliest.unwrap();
-                out.push((*earliest_func.unwrap())(element.clone(), &current_tokens)?);
-                let removed = current_tokens.splice(
-                    element.first().unwrap().start..element.last().unwrap().end,
-                    vec![]).collect::<Vec<Lexeme>>();
+                let span = element.first().unwrap().start..element.last().unwrap().end;
+                //an error from further in is already located, the others are put at the start
+                let parsed = (*earliest_func.unwrap())(element.clone(), &current_tokens)
+                    .map_err(|e| current_tokens.locate(e, span.start))?;
+                out.push(match current_tokens.locations.get(span.start) {
+                    Some(location) if class == 0 && parsed.is_expression() => {
+                        let statement = Statement::new(parsed.expression().unwrap(), location.clone());
+                        (Box::new(statement) as Box<dyn Expression>).into()
+                    }
+                    _ => parsed,
+                });
+                let removed = current_tokens.splice(span);
                 //println!("removed {:?}", removed);
                 break;
             }
@@ -440,7 +665,7 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
     }
     
     if current_tokens.len() > 0 {
-        panic!("det blev över {:?}", current_tokens);
+        return Err(current_tokens.locate(CompileError::InvalidCommandError(current_tokens.to_vec()), 0));
     }
     
     if out.len() > 0 {
@@ -448,32 +673,136 @@ fn parse_tokenized_expression(tokens: Vec<Lexeme>) -> Result<Vec<AnyExpressionTy
     }
     
     println!("hittade inget");
-    Err(CompileError::InvalidCommandError(current_tokens))
+    Err(CompileError::InvalidCommandError(current_tokens.lexemes))
+}
+
+/// Parses the lexemes in `range` of `tokens`
+fn parse_range(tokens: &Tokens, range: Range<usize>) -> Result<Vec<AnyExpressionType>, CompileError> {
+    parse_tokenized_expression(tokens.slice(range))
+}
+
+/// Parses the inside of an `asm` block. Every line is either `name:` or an instruction ending in `;`.
+/// Tiles can be written as `3`, `*3`, `[3]` or `**3`, so `#define`d names work as operands.
+fn parse_asm(tokens: &[Lexeme]) -> Result<Vec<AsmLine>, CompileError> {
+    let mut out = Vec::new();
+    let mut ptr = 0;
+    
+    while ptr < tokens.len() {
+        let name = match &tokens[ptr] {
+            Lexeme::Identifier(name) => name,
+            _ => return Err(CompileError::InvalidCommandError(tokens[ptr..].to_vec())),
+        };
+        
+        if tokens.get(ptr + 1) == Some(&Lexeme::Colon) {
+            out.push(AsmLine::Label(name.clone()));
+            ptr += 2;
+            continue;
+        }
+        
+        let end = match tokens[ptr..].iter().position(|e| e == &Lexeme::Semicolon) {
+            Some(t) => ptr + t,
+            None => return Err(CompileError::InvalidCommandError(tokens[ptr..].to_vec())),
+        };
+        let operand = &tokens[ptr + 1..end];
+        let invalid = || CompileError::InvalidCommandError(tokens[ptr..=end].to_vec());
+        
+        out.push(match name.to_uppercase().as_str() {
+            "INBOX" if operand.is_empty() => AsmLine::Command(Command::Inbox),
+            "OUTBOX" if operand.is_empty() => AsmLine::Command(Command::Outbox),
+            "COPYFROM" => AsmLine::Command(Command::CopyFrom(parse_asm_tile(operand).ok_or_else(invalid)?)),
+            "COPYTO" => AsmLine::Command(Command::CopyTo(parse_asm_tile(operand).ok_or_else(invalid)?)),
+            "ADD" => AsmLine::Command(Command::Add(parse_asm_tile(operand).ok_or_else(invalid)?)),
+            "SUB" => AsmLine::Command(Command::Subtract(parse_asm_tile(operand).ok_or_else(invalid)?)),
+            "BUMPUP" => AsmLine::Command(Command::Increment(parse_asm_tile(operand).ok_or_else(invalid)?)),
+            "BUMPDN" => AsmLine::Command(Command::Decrement(parse_asm_tile(operand).ok_or_else(invalid)?)),
+            "JUMP" => AsmLine::Jump(AsmJump::Always, parse_asm_label(operand).ok_or_else(invalid)?),
+            "JUMPZ" => AsmLine::Jump(AsmJump::IfZero, parse_asm_label(operand).ok_or_else(invalid)?),
+            "JUMPN" => AsmLine::Jump(AsmJump::IfNegative, parse_asm_label(operand).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        });
+        ptr = end + 1;
+    }
+    
+    Ok(out)
+}
+
+fn parse_asm_tile(operand: &[Lexeme]) -> Option<Reference> {
+    match operand {
+        [Lexeme::Number(n)] | [Lexeme::Star, Lexeme::Number(n)] => Some(Reference::Pointer(*n)),
+        [Lexeme::LeftSquareBracket, Lexeme::Number(n), Lexeme::RightSquareBracket] |
+        [Lexeme::Star, Lexeme::Star, Lexeme::Number(n)] => Some(Reference::PointerPointer(*n)),
+        _ => None,
+    }
+}
+
+fn parse_asm_label(operand: &[Lexeme]) -> Option<String> {
+    match operand {
+        [Lexeme::Identifier(name)] => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// Splits a list of arguments at the commas that aren't nested inside parentheses, giving where
+/// each argument is in `tokens`.
+fn split_arguments(tokens: &[Lexeme]) -> Vec<Range<usize>> {
+    if tokens.is_empty() {
+        return vec![];
+    }
+    
+    let mut out = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Lexeme::LeftParentheses => depth += 1,
+            Lexeme::RightParentheses => depth -= 1,
+            Lexeme::Comma if depth == 0 => {
+                out.push(start..i);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    out.push(start..tokens.len());
+    
+    out
+}
+
+fn is_zero(expression: &AnyExpressionType) -> bool {
+    match expression.value_ref() {
+        Some(value) => value.downcast_ref::<Number>() == Some(&Number::new(0)),
+        None => false,
+    }
 }
 
 #[derive(Debug)]
 pub struct AST {
     pub root: Vec<Box<dyn Expression>>,
+    /// The names of the labels declared with `label`, which generated labels have to stay clear of
+    pub labels: HashSet<String>,
 }
 
 impl AST {
-    fn new(expressions: Vec<AnyExpressionType>) -> Result<Self, CompileError> {
+    /// `tokens` are the lexemes that `expressions` were parsed from, which label errors point into
+    fn new(expressions: Vec<AnyExpressionType>, tokens: &Tokens) -> Result<Self, CompileError> {
         let (ok, err): (Vec<AnyExpressionType>, Vec<AnyExpressionType>) =
             expressions.into_iter().partition(|e| e.is_expression());
         
         if err.len() > 0 {
             return Err(CompileError::Error); //TODO: make me good
         }
-        Ok(Self { root: ok.into_iter()
+        let root: Vec<Box<dyn Expression>> = ok.into_iter()
             .map(|e| e.expression().unwrap())
-            .collect()})
+            .collect();
+        let labels = check_labels(&root, tokens)?;
+        Ok(Self { root, labels })
     }
     
-    fn to_commands(&self, add_addr: u8) -> Result<Vec<Command>, CompileError> { //TODO: use me
-        let mut label_counter = 0;
+    fn to_commands(&self, memory: &mut Memory) -> Result<Vec<Command>, CompileError> { //TODO: use me
+        let mut labels = LabelGenerator::new(LabelStyle::Compact);
         
         let (ok, err): (Vec<_>, Vec<_>) = self.root.iter()
-            .map(|e| e.to_command(&mut label_counter, add_addr, None))
+            .map(|e| e.to_command(&mut labels, memory, None))
             .partition(|e| e.is_ok());
     
         for e in err {
@@ -487,6 +816,66 @@ impl AST {
     }
 }
 
+/// Checks that every `goto` has exactly one label to go to, and that the label isn't inside a
+/// block that the `goto` is outside of. Gives the names of the labels.
+///
+/// Labels and gotos are found in the same order as their lexemes in `tokens`, so the errors are
+/// put at the `label` or `goto` lexeme they are about.
+fn check_labels(root: &[Box<dyn Expression>], tokens: &Tokens) -> Result<HashSet<String>, CompileError> {
+    let mut labels = Vec::new();
+    let mut gotos = Vec::new();
+    find_labels(root, &mut vec![0], &mut 0, &mut labels, &mut gotos);
+    
+    let at = |error, keyword: &Lexeme, index: usize| {
+        let position = tokens.iter()
+            .enumerate()
+            .filter(|(_, e)| *e == keyword)
+            .nth(index)
+            .map_or(0, |(i, _)| i);
+        tokens.locate(error, position)
+    };
+    
+    let mut paths = HashMap::new();
+    for (i, (name, path)) in labels.into_iter().enumerate() {
+        if paths.insert(name.clone(), path).is_some() {
+            return Err(at(CompileError::DuplicateLabel(name), &Lexeme::Label, i));
+        }
+    }
+    
+    for (i, (name, path)) in gotos.into_iter().enumerate() {
+        match paths.get(&name) {
+            None => return Err(at(CompileError::UndefinedLabel(name), &Lexeme::Goto, i)),
+            Some(label_path) if !path.starts_with(label_path) =>
+                return Err(at(CompileError::JumpIntoBlock(name), &Lexeme::Goto, i)),
+            _ => {}
+        }
+    }
+    
+    Ok(paths.into_keys().collect())
+}
+
+/// Walks through `block` and records where every label and goto is, in the order they are
+/// written. `path` lists the blocks that enclose the current one, each block getting its own
+/// number from `block_counter`.
+fn find_labels(block: &[Box<dyn Expression>], path: &mut Vec<usize>, block_counter: &mut usize,
+               labels: &mut Vec<(String, Vec<usize>)>, gotos: &mut Vec<(String, Vec<usize>)>) {
+    for expression in block {
+        let expression = Statement::inner_of(&**expression);
+        if let Some(label) = expression.downcast_ref::<UserLabel>() {
+            labels.push((label.name().to_string(), path.clone()));
+        } else if let Some(goto) = expression.downcast_ref::<Goto>() {
+            gotos.push((goto.name().to_string(), path.clone()));
+        }
+        
+        for inner in expression.blocks() {
+            *block_counter += 1;
+            path.push(*block_counter);
+            find_labels(inner, path, block_counter, labels, gotos);
+            path.pop();
+        }
+    }
+}
+
 /*#[derive(Debug)]
 pub enum Expression {
     Number(u8),
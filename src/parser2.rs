@@ -1,17 +1,86 @@
 use std::fmt::Debug;
-use crate::compiler::{Command, Reference, Label, LabelRef};
+use std::collections::{HashMap, HashSet};
+use crate::compiler::{Command, Reference, Label, LabelRef, LabelGenerator, Memory, Construct, Goal};
 use crate::CompileError;
+use crate::preprocessor::Location;
 use downcast_rs::Downcast;
 use downcast_rs::impl_downcast;
 use crate::compiler::Command::CopyTo;
 
 pub trait Expression: Debug + Downcast {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError>;
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError>;
     fn eq(&self, other: &dyn Expression) -> bool;
     fn clone(&self) -> Box<dyn Expression>;
+    
+    /// The blocks of statements nested directly inside this expression.
+    fn blocks(&self) -> Vec<&[Box<dyn Expression>]> {
+        vec![]
+    }
+    
+    /// What working out this expression changes. Expressions that don't say are assumed to write
+    /// to tiles.
+    fn side_effects(&self) -> SideEffects {
+        SideEffects::Memory
+    }
+    
+    /// Simplifies the expressions nested in this one, and gives a simpler expression that does the
+    /// same as this one if there is one.
+    fn simplify(&mut self, _memory: &Memory) -> Option<Box<dyn Expression>> {
+        None
+    }
+    
+    /// The value of this expression if it is the same every time. Expressions with a known value
+    /// never have side effects.
+    fn known_value(&self, _memory: &Memory) -> Option<i16> {
+        None
+    }
 }
 impl_downcast!(Expression);
 
+/// The numbers that fit on a tile
+pub const VALUE_RANGE: std::ops::RangeInclusive<i16> = -999..=999;
+
+/// Replaces `expression` with its simplified form
+pub fn simplify_in_place(expression: &mut Box<dyn Expression>, memory: &Memory) {
+    if let Some(simpler) = expression.simplify(memory) {
+        *expression = simpler;
+    }
+}
+
+pub fn simplify_block(contents: &mut [Box<dyn Expression>], memory: &Memory) {
+    for expression in contents {
+        simplify_in_place(expression, memory);
+    }
+}
+
+/// An expression that loads `value`, if there is a tile holding it
+pub fn known_constant(value: i16, memory: &Memory) -> Option<Box<dyn Expression>> {
+    if memory.constant(value).is_ok() {
+        Some(Box::new(Constant::new(value)))
+    } else {
+        None
+    }
+}
+
+/// The things an expression can change, from least to most. Two operands can only be worked out in
+/// a different order than they are written if the difference can't be seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SideEffects {
+    /// Only reads tiles
+    None,
+    /// Takes from the inbox
+    Io,
+    /// Writes to tiles
+    Memory,
+}
+
+impl SideEffects {
+    /// Whether `a` and `b` can be worked out in either order
+    pub fn reorderable(a: SideEffects, b: SideEffects) -> bool {
+        a.max(b) <= SideEffects::Io && a.min(b) == SideEffects::None
+    }
+}
+
 impl PartialEq for dyn Expression {
     fn eq(&self, other: &dyn Expression) -> bool {
         Expression::eq(self, other)
@@ -48,9 +117,25 @@ impl Clone for Box<dyn Value> {
 }
 
 pub trait Logical: Debug + Downcast {
-    fn to_commands(&self, end_label: LabelRef, label_counter: &mut u8, add_addr: u8) -> Result<Vec<Command>, CompileError>;
+    /// Works out the condition and goes to `targets.if_true` or `targets.if_false`.
+    fn to_commands(&self, targets: Targets, labels: &mut LabelGenerator, memory: &mut Memory) -> Result<Vec<Command>, CompileError>;
+    /// How many jumps `to_commands` needs when the code for the true case comes right after the
+    /// condition, or the code for the false case if `true_follows` is false.
+    fn jumps(&self, true_follows: bool) -> usize;
     fn eq(&self, other: &dyn Logical) -> bool;
     fn clone(&self) -> Box<dyn Logical>;
+    
+    fn side_effects(&self) -> SideEffects {
+        SideEffects::Memory
+    }
+    
+    /// Simplifies the expressions the condition is made of
+    fn simplify(&mut self, _memory: &Memory) {}
+    
+    /// Whether the condition is always true or always false
+    fn known(&self, _memory: &Memory) -> Option<bool> {
+        None
+    }
 }
 impl_downcast!(Logical);
 
@@ -68,6 +153,73 @@ impl Clone for Box<dyn Logical> {
     }
 }
 
+/// Where a condition goes when it is true and when it is false. `None` is the code right after the
+/// condition, which saves a jump, so only one of them should be `None`.
+#[derive(Debug, Clone)]
+pub struct Targets {
+    pub if_true: Option<LabelRef>,
+    pub if_false: Option<LabelRef>,
+}
+
+impl Targets {
+    /// Runs into the next command when the condition is true
+    pub fn jump_if_false(label: LabelRef) -> Self {
+        Self { if_true: None, if_false: Some(label) }
+    }
+    
+    /// Runs into the next command when the condition is false
+    pub fn jump_if_true(label: LabelRef) -> Self {
+        Self { if_true: Some(label), if_false: None }
+    }
+}
+
+/// For which values in the hand a test on the sign is true
+#[derive(Debug, Clone, Copy)]
+struct Signs {
+    zero: bool,
+    negative: bool,
+    positive: bool,
+}
+
+impl Signs {
+    /// The jumps for the test. HRM can only jump on zero and negative values, so positive values
+    /// go wherever the code after the tests goes, and zero and negative values only need a jump of
+    /// their own if they go somewhere else.
+    fn branch(self, kind: &'static str, targets: Targets, labels: &mut LabelGenerator) -> Vec<Command> {
+        let target = |holds: bool| if holds { &targets.if_true } else { &targets.if_false };
+        let mut skip = None;
+        let mut out = Vec::new();
+        
+        for (holds, jump) in [(self.zero, Command::JumpIfZero as fn(LabelRef) -> Command), (self.negative, Command::JumpIfNegative)] {
+            if holds == self.positive {
+                continue;
+            }
+            out.push(jump(match target(holds) {
+                Some(label) => label.clone(),
+                None => skip.get_or_insert_with(|| {
+                    let construct = labels.construct(kind);
+                    labels.label(&construct, "skip")
+                }).reference(),
+            }));
+        }
+        out.extend(target(self.positive).clone().map(Command::Jump));
+        out.extend(skip.map(Command::Label));
+        out
+    }
+    
+    fn holds(self, value: i16) -> bool {
+        match value {
+            0 => self.zero,
+            value if value < 0 => self.negative,
+            _ => self.positive,
+        }
+    }
+    
+    fn jumps(self, true_follows: bool) -> usize {
+        (self.zero != self.positive) as usize + (self.negative != self.positive) as usize + (self.positive != true_follows) as usize
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct AnyExpressionType {
     expression: Option<Box<dyn Expression>>,
@@ -120,6 +272,22 @@ impl AnyExpressionType {
     pub fn is_logical(&self) -> bool {
         self.logical.is_some()
     }
+    
+    /// Takes this as something that produces a value. Conditions are turned into 0 or 1.
+    pub fn into_expression(self) -> Option<Box<dyn Expression>> {
+        match self.expression {
+            Some(t) => Some(t),
+            None => self.logical.map(|l| Box::new(Materialize::new(l)) as Box<dyn Expression>),
+        }
+    }
+    
+    /// Takes this as a condition. Plain values are true when they aren't zero.
+    pub fn into_logical(self) -> Option<Box<dyn Logical>> {
+        match self.logical {
+            Some(t) => Some(t),
+            None => self.expression.map(|e| Box::new(NotZero::new(e)) as Box<dyn Logical>),
+        }
+    }
 }
 
 impl From<Box<dyn Expression>> for AnyExpressionType {
@@ -167,6 +335,42 @@ macro_rules! impl_fns {
     };
 }
 
+/// Implements `Logical` for a struct holding an `expression` whose sign is tested. The flags say
+/// whether the test is true for zero, negative and positive values.
+macro_rules! impl_sign_test {
+    ($object:ident, $kind:literal, $zero:literal, $negative:literal, $positive:literal) => {
+        impl Logical for $object {
+            fn to_commands(&self, targets: Targets, labels: &mut LabelGenerator, memory: &mut Memory) -> Result<Vec<Command>, CompileError> {
+                let signs = Signs { zero: $zero, negative: $negative, positive: $positive };
+                Ok(self.expression.to_command(labels, memory, None)?.into_iter()
+                    .chain(signs.branch($kind, targets, labels))
+                    .collect())
+            }
+            
+            fn jumps(&self, true_follows: bool) -> usize {
+                Signs { zero: $zero, negative: $negative, positive: $positive }.jumps(true_follows)
+            }
+            
+            fn side_effects(&self) -> SideEffects {
+                self.expression.side_effects()
+            }
+            
+            fn simplify(&mut self, memory: &Memory) {
+                simplify_in_place(&mut self.expression, memory);
+            }
+            
+            fn known(&self, memory: &Memory) -> Option<bool> {
+                let signs = Signs { zero: $zero, negative: $negative, positive: $positive };
+                self.expression.known_value(memory).map(|value| signs.holds(value))
+            }
+            
+            impl_fns!(Logical);
+        }
+        
+        impl_partialeq!($object, expression);
+    };
+}
+
 macro_rules! impl_partialeq {
     ($object:ident, $($params:ident),+) => {
         impl PartialEq for $object {
@@ -198,6 +402,34 @@ impl Value for Number {
     impl_fns!(Value);
 }
 
+/// A number used as a value, read from the tile declared to hold it with `#constant`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Constant {
+    value: i16,
+}
+
+impl Constant {
+    pub fn new(value: i16) -> Self {
+        Self { value }
+    }
+}
+
+impl Expression for Constant {
+    fn to_command(&self, _labels: &mut LabelGenerator, memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        Ok(vec![Command::CopyFrom(memory.constant(self.value)?)])
+    }
+    
+    fn side_effects(&self) -> SideEffects {
+        SideEffects::None
+    }
+    
+    fn known_value(&self, _memory: &Memory) -> Option<i16> {
+        Some(self.value)
+    }
+    
+    impl_fns!(Expression);
+}
+
 #[derive(Debug, Clone)]
 pub struct Output {
     argument: Box<dyn Expression>,
@@ -212,12 +444,17 @@ impl Output {
 }
 
 impl Expression for Output {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
-        Ok(self.argument.to_command(label_counter, add_addr, inside_block)?.into_iter()
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        Ok(self.argument.to_command(labels, memory, inside_block)?.into_iter()
             .chain(vec![Command::Outbox])
             .collect())
     }
     
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.argument, memory);
+        None
+    }
+    
     impl_fns!(Expression);
 }
 
@@ -225,10 +462,14 @@ impl Expression for Output {
 pub struct Input {}
 
 impl Expression for Input {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
         Ok(vec![Command::Inbox])
     }
     
+    fn side_effects(&self) -> SideEffects {
+        SideEffects::Io
+    }
+    
     impl_fns!(Expression);
 }
 
@@ -244,10 +485,18 @@ impl Deref {
 }
 
 impl Expression for Deref {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
         return Ok(vec![Command::CopyFrom(self.value()?)]);
     }
     
+    fn side_effects(&self) -> SideEffects {
+        SideEffects::None
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        memory.constant_value(&self.value().ok()?)
+    }
+    
     impl_fns!(Expression);
 }
 
@@ -282,14 +531,61 @@ impl Add {
 }
 
 impl Expression for Add {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
-        Ok(self.left.to_command(label_counter, add_addr, inside_block)?.into_iter()
-            .chain(vec![CopyTo(Reference::Pointer(add_addr))].into_iter())
-            .chain(self.right.to_command(label_counter, add_addr, inside_block)?)
-            .chain(vec![Command::Add(Reference::Pointer(add_addr))].into_iter())
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        if let Some(right) = self.right.downcast_ref::<Deref>() {
+            return Ok(self.left.to_command(labels, memory, inside_block)?.into_iter()
+                .chain(vec![Command::Add(right.value()?)])
+                .collect());
+        }
+        //adding goes both ways, so a tile on the left can be added to the right value instead
+        if let Some(left) = self.left.downcast_ref::<Deref>() {
+            if SideEffects::reorderable(SideEffects::None, self.right.side_effects()) {
+                return Ok(self.right.to_command(labels, memory, inside_block)?.into_iter()
+                    .chain(vec![Command::Add(left.value()?)])
+                    .collect());
+            }
+        }
+        
+        let left = self.left.to_command(labels, memory, inside_block)?;
+        //the left value is kept in the temporary while the right one is worked out
+        let temporary = memory.allocate()?;
+        let right = self.right.to_command(labels, memory, inside_block)?;
+        memory.release(&temporary);
+        
+        Ok(left.into_iter()
+            .chain(vec![CopyTo(temporary.clone())].into_iter())
+            .chain(right)
+            .chain(vec![Command::Add(temporary)].into_iter())
             .collect())
     }
     
+    fn side_effects(&self) -> SideEffects {
+        self.left.side_effects().max(self.right.side_effects())
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.left, memory);
+        simplify_in_place(&mut self.right, memory);
+        if let Some(sum) = self.known_value(memory) {
+            if let Some(constant) = known_constant(sum, memory) {
+                return Some(constant);
+            }
+        }
+        
+        if self.right.known_value(memory) == Some(0) {
+            Some(self.left.clone())
+        } else if self.left.known_value(memory) == Some(0) {
+            Some(self.right.clone())
+        } else {
+            None
+        }
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        //a sum that doesn't fit on a tile is an error when the program runs, so it has to stay
+        self.left.known_value(memory)?.checked_add(self.right.known_value(memory)?).filter(|e| VALUE_RANGE.contains(e))
+    }
+    
     impl_fns!(Expression);
 }
 
@@ -308,14 +604,82 @@ impl Subtract {
 }
 
 impl Expression for Subtract {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
-        Ok(self.right.to_command(label_counter, add_addr, inside_block)?.into_iter()
-            .chain(vec![CopyTo(Reference::Pointer(add_addr))])
-            .chain(self.left.to_command(label_counter, add_addr, inside_block)?)
-            .chain(vec![Command::Subtract(Reference::Pointer(add_addr))])
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        if let Some(right) = self.right.downcast_ref::<Deref>() {
+            return Ok(self.left.to_command(labels, memory, inside_block)?.into_iter()
+                .chain(vec![Command::Subtract(right.value()?)])
+                .collect());
+        }
+        
+        //SUB needs the right value in a tile, so working it out first saves a tile and two commands
+        if SideEffects::reorderable(self.left.side_effects(), self.right.side_effects()) {
+            let right = self.right.to_command(labels, memory, inside_block)?;
+            let temporary = memory.allocate()?;
+            let left = self.left.to_command(labels, memory, inside_block)?;
+            memory.release(&temporary);
+            
+            return Ok(right.into_iter()
+                .chain(vec![CopyTo(temporary.clone())])
+                .chain(left)
+                .chain(vec![Command::Subtract(temporary)])
+                .collect());
+        }
+        
+        let left = self.left.to_command(labels, memory, inside_block)?;
+        let left_temporary = memory.allocate()?;
+        let right = self.right.to_command(labels, memory, inside_block)?;
+        let right_temporary = memory.allocate();
+        memory.release(&left_temporary);
+        let store_left = CopyTo(left_temporary.clone());
+        let tail = match right_temporary {
+            Ok(right_temporary) => {
+                memory.release(&right_temporary);
+                vec![CopyTo(right_temporary.clone()),
+                     Command::CopyFrom(left_temporary.clone()),
+                     Command::Subtract(right_temporary)]
+            }
+            //with only one tile, right - left is worked out and put on it. Taking that away from
+            //itself gives 0, and taking it away once more gives left - right.
+            Err(_) => vec![Command::Subtract(left_temporary.clone()),
+                           CopyTo(left_temporary.clone()),
+                           Command::Subtract(left_temporary.clone()),
+                           Command::Subtract(left_temporary)],
+        };
+        
+        Ok(left.into_iter()
+            .chain(vec![store_left])
+            .chain(right)
+            .chain(tail)
             .collect())
     }
     
+    fn side_effects(&self) -> SideEffects {
+        self.left.side_effects().max(self.right.side_effects())
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.left, memory);
+        simplify_in_place(&mut self.right, memory);
+        if let Some(difference) = self.known_value(memory) {
+            if let Some(constant) = known_constant(difference, memory) {
+                return Some(constant);
+            }
+        }
+        
+        if self.right.known_value(memory) == Some(0) {
+            return Some(self.left.clone());
+        }
+        //reading the same tiles twice gives the same value, so the difference is zero
+        if *self.left == *self.right && self.left.side_effects() == SideEffects::None {
+            return known_constant(0, memory);
+        }
+        None
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        self.left.known_value(memory)?.checked_sub(self.right.known_value(memory)?).filter(|e| VALUE_RANGE.contains(e))
+    }
+    
     impl_fns!(Expression);
 }
 
@@ -332,29 +696,139 @@ impl Loop {
     }
 }
 
+/// Loops with a body of up to this many commands have it written out twice when optimising for
+/// speed, so only every other round needs the jump back to the top
+const UNROLL_LIMIT: usize = 8;
+
 impl Expression for Loop {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
-        let top_label = Label::new(label_counter);
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("loop");
+        let top_label = labels.label(&construct, "top");
         let top_label_ref = LabelRef::new(&top_label);
-        let out_label = Label::new(label_counter);
-        let (ok_contents, err_contents): (Vec<Result<Vec<Command>, CompileError>>, Vec<Result<Vec<Command>, CompileError>>) =
-            self.contents.iter()
-            .map(|e| e.to_command(label_counter, add_addr, Some(&out_label)))
-            .partition(|e| e.is_ok());
-        for err_content in err_contents {
-            return err_content;
+        let out_label = labels.label(&construct, "end");
+        let mut contents = compile_block(&self.contents, labels, memory, Some(&out_label))?;
+        if memory.goal() == Goal::Speed && contents.len() <= UNROLL_LIMIT {
+            let copy = compile_block(&self.contents, labels, memory, Some(&out_label))?;
+            //labels from the program itself would be there twice
+            if !shares_labels(&contents, &copy) {
+                contents.extend(copy);
+            }
         }
+        
         Ok(vec![Command::Label(top_label)].into_iter()
-            .chain(ok_contents.into_iter()
-                .map(|e| e.unwrap())
-                .flatten())
+            .chain(contents)
             .chain(vec![Command::Jump(top_label_ref), Command::Label(out_label)])
             .collect())
     }
     
+    fn blocks(&self) -> Vec<&[Box<dyn Expression>]> {
+        vec![&self.contents]
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_block(&mut self.contents, memory);
+        //a body that is the same statements a few times over does the same as one round of them
+        if memory.goal() == Goal::Size {
+            let period = repeats_every(&self.contents);
+            self.contents.truncate(period);
+        }
+        None
+    }
+    
     impl_fns!(Expression);
 }
 
+/// The length of the shortest run of statements that `contents` is made of, written out one or
+/// more times. Where the statements are in the file doesn't matter.
+fn repeats_every(contents: &[Box<dyn Expression>]) -> usize {
+    (1..contents.len())
+        .filter(|&period| contents.len().is_multiple_of(period))
+        .find(|&period| (period..contents.len())
+            .all(|i| Statement::inner_of(&*contents[i]) == Statement::inner_of(&*contents[i - period])))
+        .unwrap_or(contents.len())
+}
+
+/// Statements that are run one after the other, what is left of a conditional whose outcome is
+/// known
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Sequence {
+    contents: Vec<Box<dyn Expression>>,
+}
+
+impl Sequence {
+    pub fn new(contents: Vec<Box<dyn Expression>>) -> Self {
+        Self { contents }
+    }
+}
+
+impl Expression for Sequence {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        compile_block(&self.contents, labels, memory, inside_block)
+    }
+    
+    fn blocks(&self) -> Vec<&[Box<dyn Expression>]> {
+        vec![&self.contents]
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_block(&mut self.contents, memory);
+        None
+    }
+    
+    impl_fns!(Expression);
+}
+
+/// A statement of the program and where it is written, so errors from compiling it can say where
+/// they are
+#[derive(Debug, Clone)]
+pub struct Statement {
+    inner: Box<dyn Expression>,
+    location: Location,
+}
+
+impl Statement {
+    pub fn new(inner: Box<dyn Expression>, location: Location) -> Self {
+        Self { inner, location }
+    }
+    
+    /// What `expression` does, without the `Statement` around it if there is one
+    pub fn inner_of(expression: &dyn Expression) -> &dyn Expression {
+        match expression.downcast_ref::<Statement>() {
+            Some(statement) => &*statement.inner,
+            None => expression,
+        }
+    }
+}
+
+impl Expression for Statement {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        self.inner.to_command(labels, memory, inside_block)
+            .and_then(|commands| memory.check_writes(&commands).map(|_| commands))
+            .map_err(|e| e.at(&self.location))
+    }
+    
+    fn blocks(&self) -> Vec<&[Box<dyn Expression>]> {
+        self.inner.blocks()
+    }
+    
+    fn side_effects(&self) -> SideEffects {
+        self.inner.side_effects()
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.inner, memory);
+        None
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        self.inner.known_value(memory)
+    }
+    
+    impl_fns!(Expression);
+}
+
+impl_partialeq!(Statement, inner, location);
+
 #[derive(Debug, Clone)]
 pub struct Assign {
     left: Box<dyn Value>,
@@ -368,12 +842,17 @@ impl Assign {
 }
 
 impl Expression for Assign {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
-        Ok(self.right.to_command(label_counter, add_addr, inside_block)?.into_iter()
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        Ok(self.right.to_command(labels, memory, inside_block)?.into_iter()
             .chain(vec![Command::CopyTo(self.left.value()?)].into_iter())
             .collect())
     }
     
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        simplify_in_place(&mut self.right, memory);
+        None
+    }
+    
     impl_fns!(Expression);
 }
 
@@ -392,19 +871,20 @@ impl If {
 }
 
 impl Expression for If {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
-        let end_true_label = Label::new(label_counter);
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("if");
+        let end_true_label = labels.label(&construct, "end");
         let ref_to = end_true_label.reference();
         
         let (ok, err): (Vec<Result<Vec<Command>, CompileError>>, Vec<Result<Vec<Command>, CompileError>>) = self.to_run.iter()
-            .map(|e| e.to_command(label_counter, add_addr, inside_block))
+            .map(|e| e.to_command(labels, memory, inside_block))
             .partition(|e| e.is_ok());
     
         for e in err {
             return e;
         }
         
-        Ok(self.condition.to_commands(ref_to, label_counter, add_addr)?.into_iter()
+        Ok(self.condition.to_commands(Targets::jump_if_false(ref_to), labels, memory)?.into_iter()
             .chain(ok.into_iter()
                 .map(|e| e.unwrap())
                 .flatten())
@@ -412,6 +892,19 @@ impl Expression for If {
             .collect())
     }
     
+    fn blocks(&self) -> Vec<&[Box<dyn Expression>]> {
+        vec![&self.to_run]
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        self.condition.simplify(memory);
+        simplify_block(&mut self.to_run, memory);
+        match self.condition.known(memory)? {
+            true => Some(Box::new(Sequence::new(self.to_run.clone()))),
+            false => Some(Box::new(Sequence::new(vec![]))),
+        }
+    }
+    
     impl_fns!(Expression);
 }
 
@@ -431,136 +924,330 @@ impl IfElse {
 }
 
 impl Expression for IfElse {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
-        let first_label = Label::new(label_counter);
-        let second_label = Label::new(label_counter);
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("if");
+        let if_true = compile_block(&self.if_true, labels, memory, inside_block)?;
+        let if_false = compile_block(&self.if_false, labels, memory, inside_block)?;
+        compile_arms(&*self.condition, if_true, if_false, &construct, labels, memory)
+    }
     
-        let (ok_true, err): (Vec<Result<Vec<Command>, CompileError>>, Vec<Result<Vec<Command>, CompileError>>) = self.if_true.iter()
-            .map(|e| e.to_command(label_counter, add_addr, inside_block))
-            .partition(|e| e.is_ok());
+    fn blocks(&self) -> Vec<&[Box<dyn Expression>]> {
+        vec![&self.if_true, &self.if_false]
+    }
     
-        for e in err {
-            return e;
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        self.condition.simplify(memory);
+        simplify_block(&mut self.if_true, memory);
+        simplify_block(&mut self.if_false, memory);
+        match self.condition.known(memory)? {
+            true => Some(Box::new(Sequence::new(self.if_true.clone()))),
+            false => Some(Box::new(Sequence::new(self.if_false.clone()))),
         }
+    }
     
-        let (ok_false, err): (Vec<Result<Vec<Command>, CompileError>>, Vec<Result<Vec<Command>, CompileError>>) = self.if_false.iter()
-            .map(|e| e.to_command(label_counter, add_addr, inside_block))
-            .partition(|e| e.is_ok());
+    impl_fns!(Expression);
+}
+
+impl_partialeq!(IfElse, condition, if_true, if_false);
+
+type Branch = (Box<dyn Logical>, Vec<Box<dyn Expression>>);
+
+/// An `if` followed by any number of `else if` arms and an optional `else`. Every arm jumps to the
+/// same exit label, so nested chains don't turn into jumps to jumps.
+#[derive(Debug, Clone)]
+pub struct IfChain {
+    branches: Vec<Branch>,
+    otherwise: Vec<Box<dyn Expression>>,
+}
+
+impl IfChain {
+    pub fn new(branches: Vec<Branch>, otherwise: Vec<Box<dyn Expression>>) -> Self {
+        Self { branches, otherwise }
+    }
     
-        for e in err {
-            return e;
+    /// Builds `if (condition) {if_true} else {if_false}`. When the else block is nothing but another
+    /// conditional, its arms are pulled up into one flat chain.
+    pub fn flatten(condition: Box<dyn Logical>, if_true: Vec<Box<dyn Expression>>, mut if_false: Vec<Box<dyn Expression>>) -> Box<dyn Expression> {
+        let conditional = |e: &dyn Expression| e.is::<If>() || e.is::<IfElse>() || e.is::<IfChain>();
+        if if_false.len() != 1 || !conditional(Statement::inner_of(&*if_false[0])) {
+            return Box::new(IfElse::new(condition, if_true, if_false));
         }
         
-        Ok(self.condition.to_commands(first_label.reference(), label_counter, add_addr)?.into_iter()
-            .chain(ok_true.into_iter()
-                .map(|e| e.unwrap())
-                .flatten())
-            .chain(vec![Command::Jump(second_label.reference()), Command::Label(first_label)])
-            .chain(ok_false.into_iter()
-                .map(|e| e.unwrap())
-                .flatten())
-            .chain(vec![Command::Label(second_label)])
-            .collect())
+        let mut branches = vec![(condition, if_true)];
+        let nested = if_false.remove(0);
+        let nested = match nested.downcast::<Statement>() {
+            Ok(statement) => statement.inner,
+            Err(nested) => nested,
+        };
+        let otherwise = match nested.downcast::<If>() {
+            Ok(inner) => {
+                let inner = *inner;
+                branches.push((inner.condition, inner.to_run));
+                vec![]
+            }
+            Err(other) => match other.downcast::<IfElse>() {
+                Ok(inner) => {
+                    let inner = *inner;
+                    branches.push((inner.condition, inner.if_true));
+                    inner.if_false
+                }
+                Err(other) => match other.downcast::<IfChain>() {
+                    Ok(inner) => {
+                        let inner = *inner;
+                        branches.extend(inner.branches);
+                        inner.otherwise
+                    }
+                    Err(other) => {
+                        let (condition, if_true) = branches.remove(0);
+                        return Box::new(IfElse::new(condition, if_true, vec![other]));
+                    }
+                }
+            }
+        };
+        
+        Box::new(IfChain::new(branches, otherwise))
+    }
+}
+
+impl Expression for IfChain {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("if");
+        let exit_label = labels.label(&construct, "end");
+        let mut out = Vec::new();
+        
+        for (i, (condition, to_run)) in self.branches.iter().enumerate() {
+            if i + 1 == self.branches.len() && self.otherwise.is_empty() {
+                out.extend(condition.to_commands(Targets::jump_if_false(exit_label.reference()), labels, memory)?);
+                out.extend(compile_block(to_run, labels, memory, inside_block)?);
+            } else {
+                let next_label = labels.label(&construct, &format!("next_{}", i + 1));
+                out.extend(condition.to_commands(Targets::jump_if_false(next_label.reference()), labels, memory)?);
+                out.extend(compile_block(to_run, labels, memory, inside_block)?);
+                out.push(Command::Jump(exit_label.reference()));
+                out.push(Command::Label(next_label));
+            }
+        }
+        
+        out.extend(compile_block(&self.otherwise, labels, memory, inside_block)?);
+        out.push(Command::Label(exit_label));
+        Ok(out)
+    }
+    
+    fn blocks(&self) -> Vec<&[Box<dyn Expression>]> {
+        self.branches.iter()
+            .map(|e| &e.1[..])
+            .chain(std::iter::once(&self.otherwise[..]))
+            .collect()
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        let mut branches = Vec::new();
+        for (mut condition, mut to_run) in self.branches.drain(..) {
+            condition.simplify(memory);
+            simplify_block(&mut to_run, memory);
+            match condition.known(memory) {
+                //the arms after one that is always taken can never run
+                Some(true) => {
+                    self.otherwise = to_run;
+                    break;
+                }
+                Some(false) => {}
+                None => branches.push((condition, to_run)),
+            }
+        }
+        self.branches = branches;
+        simplify_block(&mut self.otherwise, memory);
+        
+        if self.branches.is_empty() {
+            Some(Box::new(Sequence::new(self.otherwise.clone())))
+        } else {
+            None
+        }
     }
     
     impl_fns!(Expression);
 }
 
-impl_partialeq!(IfElse, condition, if_true, if_false);
+impl_partialeq!(IfChain, branches, otherwise);
+
+fn compile_block(contents: &[Box<dyn Expression>], labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+    let mut out = Vec::new();
+    for expression in contents {
+        out.extend(expression.to_command(labels, memory, inside_block)?);
+    }
+    Ok(out)
+}
+
+/// Whether the two pieces of code have a label with the same name
+fn shares_labels(first: &[Command], second: &[Command]) -> bool {
+    let names: HashSet<&str> = first.iter()
+        .filter_map(|e| match e {
+            Command::Label(label) => Some(label.name()),
+            _ => None,
+        })
+        .collect();
+    second.iter().any(|e| matches!(e, Command::Label(label) if names.contains(label.name())))
+}
+
+/// Puts the commands for `if_true` and `if_false` behind `condition`. The arm that comes right
+/// after the condition is the one that lets it get by with fewer jumps.
+fn compile_arms(condition: &dyn Logical, if_true: Vec<Command>, if_false: Vec<Command>, construct: &Construct, labels: &mut LabelGenerator, memory: &mut Memory) -> Result<Vec<Command>, CompileError> {
+    let swap = condition.jumps(false) < condition.jumps(true);
+    let second_label = labels.label(construct, if swap { "true" } else { "false" });
+    let end_label = labels.label(construct, "end");
+    let (targets, first, second) = if swap {
+        (Targets::jump_if_true(second_label.reference()), if_false, if_true)
+    } else {
+        (Targets::jump_if_false(second_label.reference()), if_true, if_false)
+    };
+    
+    Ok(condition.to_commands(targets, labels, memory)?.into_iter()
+        .chain(first)
+        .chain(vec![Command::Jump(end_label.reference()), Command::Label(second_label)])
+        .chain(second)
+        .chain(vec![Command::Label(end_label)])
+        .collect())
+}
 
+/// `condition ? if_true : if_false`
 #[derive(Debug, Clone)]
-pub struct IsZero {
-    expression: Box<dyn Expression>,
+pub struct Conditional {
+    condition: Box<dyn Logical>,
+    if_true: Box<dyn Expression>,
+    if_false: Box<dyn Expression>,
 }
 
-impl IsZero {
-    pub fn new(expression: Box<dyn Expression>) -> Self {
-        Self { expression }
+impl Conditional {
+    pub fn new(condition: Box<dyn Logical>, if_true: Box<dyn Expression>, if_false: Box<dyn Expression>) -> Self {
+        Self { condition, if_true, if_false }
     }
 }
 
-impl Logical for IsZero {
-    fn to_commands(&self, end_label: LabelRef, label_counter: &mut u8, add_addr: u8) -> Result<Vec<Command>, CompileError> {
-        let new_label = Label::new(label_counter);
-        Ok(self.expression.to_command(label_counter, add_addr, None)?.into_iter()
-            .chain(vec![Command::JumpIfZero(new_label.reference()), Command::Jump(end_label), Command::Label(new_label)])
-            .collect())
+impl Expression for Conditional {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("conditional");
+        let if_true = self.if_true.to_command(labels, memory, inside_block)?;
+        let if_false = self.if_false.to_command(labels, memory, inside_block)?;
+        compile_arms(&*self.condition, if_true, if_false, &construct, labels, memory)
     }
     
-    impl_fns!(Logical);
+    fn side_effects(&self) -> SideEffects {
+        self.condition.side_effects()
+            .max(self.if_true.side_effects())
+            .max(self.if_false.side_effects())
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        self.condition.simplify(memory);
+        simplify_in_place(&mut self.if_true, memory);
+        simplify_in_place(&mut self.if_false, memory);
+        match self.condition.known(memory)? {
+            true => Some(self.if_true.clone()),
+            false => Some(self.if_false.clone()),
+        }
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        match self.condition.known(memory)? {
+            true => self.if_true.known_value(memory),
+            false => self.if_false.known_value(memory),
+        }
+    }
+    
+    impl_fns!(Expression);
 }
 
-impl_partialeq!(IsZero, expression);
+impl_partialeq!(Conditional, condition, if_true, if_false);
 
+/// A condition used as a value. Picks up 1 or 0 from the constant tiles depending on the outcome.
 #[derive(Debug, Clone)]
-pub struct NotZero {
-    expression: Box<dyn Expression>,
+pub struct Materialize {
+    condition: Box<dyn Logical>,
 }
 
-impl NotZero {
-    pub fn new(expression: Box<dyn Expression>) -> Self {
-        Self { expression }
+impl Materialize {
+    pub fn new(condition: Box<dyn Logical>) -> Self {
+        Self { condition }
     }
 }
 
-impl Logical for NotZero {
-    fn to_commands(&self, end_label: LabelRef, label_counter: &mut u8, add_addr: u8) -> Result<Vec<Command>, CompileError> {
-        Ok(self.expression.to_command(label_counter, add_addr, None)?.into_iter()
-            .chain(vec![Command::JumpIfZero(end_label)])
-            .collect())
+impl Expression for Materialize {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let one = memory.constant(1)?;
+        let zero = memory.constant(0)?;
+        let construct = labels.construct("boolean");
+        compile_arms(&*self.condition, vec![Command::CopyFrom(one)], vec![Command::CopyFrom(zero)], &construct, labels, memory)
+    }
+    
+    fn side_effects(&self) -> SideEffects {
+        self.condition.side_effects()
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        self.condition.simplify(memory);
+        known_constant(self.known_value(memory)?, memory)
+    }
+    
+    fn known_value(&self, memory: &Memory) -> Option<i16> {
+        self.condition.known(memory).map(i16::from)
     }
     
-    impl_fns!(Logical);
+    impl_fns!(Expression);
 }
 
-impl_partialeq!(NotZero, expression);
+impl_partialeq!(Materialize, condition);
 
 #[derive(Debug, Clone)]
-pub struct GreaterThanZero {
+pub struct IsZero {
     expression: Box<dyn Expression>,
 }
 
-impl GreaterThanZero {
+impl IsZero {
     pub fn new(expression: Box<dyn Expression>) -> Self {
         Self { expression }
     }
 }
 
-impl Logical for GreaterThanZero {
-    fn to_commands(&self, end_label: LabelRef, label_counter: &mut u8, add_addr: u8) -> Result<Vec<Command>, CompileError> {
-        Ok(self.expression.to_command(label_counter, add_addr, None)?.into_iter()
-            .chain(vec![Command::JumpIfZero(end_label.clone()), Command::JumpIfNegative(end_label)])
-            .collect())
+impl_sign_test!(IsZero, "is_zero", true, false, false);
+
+#[derive(Debug, Clone)]
+pub struct NotZero {
+    expression: Box<dyn Expression>,
+}
+
+impl NotZero {
+    pub fn new(expression: Box<dyn Expression>) -> Self {
+        Self { expression }
     }
-    
-    impl_fns!(Logical);
 }
 
-impl_partialeq!(GreaterThanZero, expression);
+impl_sign_test!(NotZero, "not_zero", false, true, true);
 
 #[derive(Debug, Clone)]
-pub struct LessThanZero {
+pub struct GreaterThanZero {
     expression: Box<dyn Expression>,
 }
 
-impl LessThanZero {
+impl GreaterThanZero {
     pub fn new(expression: Box<dyn Expression>) -> Self {
         Self { expression }
     }
 }
 
-impl Logical for LessThanZero {
-    fn to_commands(&self, end_label: LabelRef, label_counter: &mut u8, add_addr: u8) -> Result<Vec<Command>, CompileError> {
-        let label = Label::new(label_counter);
-        Ok(self.expression.to_command(label_counter, add_addr, None)?.into_iter()
-            .chain(vec![Command::JumpIfNegative(label.reference()), Command::Jump(end_label), Command::Label(label)])
-            .collect())
+impl_sign_test!(GreaterThanZero, "greater_than_zero", false, false, true);
+
+#[derive(Debug, Clone)]
+pub struct LessThanZero {
+    expression: Box<dyn Expression>,
+}
+
+impl LessThanZero {
+    pub fn new(expression: Box<dyn Expression>) -> Self {
+        Self { expression }
     }
-    
-    impl_fns!(Logical);
 }
 
-impl_partialeq!(LessThanZero, expression);
+impl_sign_test!(LessThanZero, "less_than_zero", false, true, false);
 
 #[derive(Debug, Clone)]
 pub struct GreaterOrEqualToZero {
@@ -573,17 +1260,7 @@ impl GreaterOrEqualToZero {
     }
 }
 
-impl Logical for GreaterOrEqualToZero {
-    fn to_commands(&self, end_label: LabelRef, label_counter: &mut u8, add_addr: u8) -> Result<Vec<Command>, CompileError> {
-        Ok(self.expression.to_command(label_counter, add_addr, None)?.into_iter()
-            .chain(vec![Command::JumpIfNegative(end_label)])
-            .collect())
-    }
-    
-    impl_fns!(Logical);
-}
-
-impl_partialeq!(GreaterOrEqualToZero, expression);
+impl_sign_test!(GreaterOrEqualToZero, "at_least_zero", true, false, true);
 
 #[derive(Debug, Clone)]
 pub struct LessOrEqualToZero {
@@ -596,21 +1273,7 @@ impl LessOrEqualToZero {
     }
 }
 
-impl Logical for LessOrEqualToZero {
-    fn to_commands(&self, end_label: LabelRef, label_counter: &mut u8, add_addr: u8) -> Result<Vec<Command>, CompileError> {
-        let label = Label::new(label_counter);
-        Ok(self.expression.to_command(label_counter, add_addr, None)?.into_iter()
-            .chain(vec![Command::JumpIfNegative(label.reference()),
-                        Command::JumpIfZero(label.reference()),
-                        Command::Jump(end_label),
-                        Command::Label(label)])
-            .collect())
-    }
-    
-    impl_fns!(Logical);
-}
-
-impl_partialeq!(LessOrEqualToZero, expression);
+impl_sign_test!(LessOrEqualToZero, "at_most_zero", true, true, false);
 
 #[derive(Debug, Clone)]
 pub struct Increment {
@@ -624,7 +1287,7 @@ impl Increment {
 }
 
 impl Expression for Increment {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
         Ok(vec![Command::Increment(self.to_increment.value()?)])
     }
     
@@ -645,7 +1308,7 @@ impl Decrement {
 }
 
 impl Expression for Decrement {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
         Ok(vec![Command::Decrement(self.to_decrement.value()?)])
     }
     
@@ -667,28 +1330,61 @@ impl While {
 }
 
 impl Expression for While {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
-        let top_label = Label::new(label_counter);
-        let top_label_ref = top_label.reference();
-        let bottom_label = Label::new(label_counter);
-    
-        let (ok, err): (Vec<Result<Vec<Command>, CompileError>>, Vec<Result<Vec<Command>, CompileError>>) = self.contents.iter()
-            .map(|e| e.to_command(label_counter, add_addr, Some(&bottom_label)))
-            .partition(|e| e.is_ok());
-    
-        for e in err {
-            return e;
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("while");
+        let bottom_label = labels.label(&construct, "end");
+        let contents = compile_block(&self.contents, labels, memory, Some(&bottom_label))?;
+        
+        //when optimising for speed the test is also written out after the body, which saves the
+        //jump back to the top in every round
+        if memory.goal() == Goal::Speed {
+            let body_label = labels.label(&construct, "body");
+            let body_label_ref = body_label.reference();
+            return Ok(self.condition.to_commands(Targets::jump_if_false(bottom_label.reference()), labels, memory)?.into_iter()
+                .chain(vec![Command::Label(body_label)])
+                .chain(contents)
+                .chain(self.condition.to_commands(Targets::jump_if_true(body_label_ref), labels, memory)?)
+                .chain(vec![Command::Label(bottom_label)])
+                .collect());
+        }
+        
+        //if the test takes fewer jumps when it goes on to the end if it fails, it is put after the
+        //body and jumped to the first time. That is just as long but each round takes fewer steps.
+        if self.condition.jumps(false) < self.condition.jumps(true) {
+            let test_label = labels.label(&construct, "test");
+            let test_label_ref = test_label.reference();
+            let body_label = labels.label(&construct, "body");
+            let body_label_ref = body_label.reference();
+            return Ok(vec![Command::Jump(test_label_ref), Command::Label(body_label)].into_iter()
+                .chain(contents)
+                .chain(vec![Command::Label(test_label)])
+                .chain(self.condition.to_commands(Targets::jump_if_true(body_label_ref), labels, memory)?)
+                .chain(vec![Command::Label(bottom_label)])
+                .collect());
         }
         
+        let top_label = labels.label(&construct, "top");
+        let top_label_ref = top_label.reference();
         Ok(vec![Command::Label(top_label)].into_iter()
-            .chain(self.condition.to_commands(bottom_label.reference(), label_counter, add_addr)?)
-            .chain(ok.into_iter()
-                .map(|e| e.unwrap())
-                .flatten())
+            .chain(self.condition.to_commands(Targets::jump_if_false(bottom_label.reference()), labels, memory)?)
+            .chain(contents)
             .chain(vec![Command::Jump(top_label_ref), Command::Label(bottom_label)])
             .collect())
     }
     
+    fn blocks(&self) -> Vec<&[Box<dyn Expression>]> {
+        vec![&self.contents]
+    }
+    
+    fn simplify(&mut self, memory: &Memory) -> Option<Box<dyn Expression>> {
+        self.condition.simplify(memory);
+        simplify_block(&mut self.contents, memory);
+        match self.condition.known(memory)? {
+            true => Some(Box::new(Loop::new(self.contents.clone()))),
+            false => Some(Box::new(Sequence::new(vec![]))),
+        }
+    }
+    
     impl_fns!(Expression);
 }
 
@@ -698,7 +1394,7 @@ impl_partialeq!(While, condition, contents);
 pub struct Break {}
 
 impl Expression for Break {
-    fn to_command(&self, label_counter: &mut u8, add_addr: u8, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+    fn to_command(&self, labels: &mut LabelGenerator, memory: &mut Memory, inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
         match inside_block {
             Some(t) => Ok(vec![Command::Jump(t.reference())]),
             None => Err(CompileError::Error),
@@ -706,4 +1402,208 @@ impl Expression for Break {
     }
     
     impl_fns!(Expression);
-}
\ No newline at end of file
+}
+
+/// `label name:`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UserLabel {
+    name: String,
+}
+
+impl UserLabel {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
+    
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Expression for UserLabel {
+    fn to_command(&self, _labels: &mut LabelGenerator, _memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        Ok(vec![Command::Label(Label::named(&self.name))])
+    }
+    
+    impl_fns!(Expression);
+}
+
+/// `goto name;`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Goto {
+    name: String,
+}
+
+impl Goto {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
+    
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Expression for Goto {
+    fn to_command(&self, _labels: &mut LabelGenerator, _memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        Ok(vec![Command::Jump(LabelRef::named(&self.name))])
+    }
+    
+    impl_fns!(Expression);
+}
+
+/// One line of an `asm` block. Jumps and labels are kept by name until the block is compiled, at
+/// which point every name gets a fresh label so that it can't clash with anything outside.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmLine {
+    Command(Command),
+    Jump(AsmJump, String),
+    Label(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsmJump {
+    Always,
+    IfZero,
+    IfNegative,
+}
+
+/// `asm { COPYFROM 3; ADD 4; JUMPN done; done: }`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Asm {
+    lines: Vec<AsmLine>,
+}
+
+impl Asm {
+    /// Fails if a jump goes to a label that isn't in the block, or if a label is declared twice.
+    pub fn new(lines: Vec<AsmLine>) -> Result<Self, CompileError> {
+        let mut declared = HashSet::new();
+        for line in &lines {
+            if let AsmLine::Label(name) = line {
+                if !declared.insert(name) {
+                    return Err(CompileError::DuplicateLabel(name.clone()));
+                }
+            }
+        }
+        
+        for line in &lines {
+            if let AsmLine::Jump(_, name) = line {
+                if !declared.contains(name) {
+                    return Err(CompileError::UndefinedLabel(name.clone()));
+                }
+            }
+        }
+        
+        Ok(Self { lines })
+    }
+}
+
+impl Expression for Asm {
+    fn to_command(&self, labels: &mut LabelGenerator, _memory: &mut Memory, _inside_block: Option<&Label>) -> Result<Vec<Command>, CompileError> {
+        let construct = labels.construct("asm");
+        let mut names = HashMap::new();
+        for line in &self.lines {
+            if let AsmLine::Label(name) = line {
+                names.insert(name, labels.label(&construct, name));
+            }
+        }
+        
+        Ok(self.lines.iter()
+            .map(|line| match line {
+                AsmLine::Command(command) => command.clone(),
+                AsmLine::Jump(kind, name) => {
+                    let target = names[name].reference();
+                    match kind {
+                        AsmJump::Always => Command::Jump(target),
+                        AsmJump::IfZero => Command::JumpIfZero(target),
+                        AsmJump::IfNegative => Command::JumpIfNegative(target),
+                    }
+                }
+                AsmLine::Label(name) => Command::Label(names[name].clone()),
+            })
+            .collect())
+    }
+    
+    impl_fns!(Expression);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::LabelStyle;
+    
+    fn tile(number: u8) -> Box<Deref> {
+        Box::new(Deref::new(Box::new(Number::new(number))))
+    }
+    
+    fn compile(expression: &dyn Expression, scratch: Vec<u8>) -> Vec<Command> {
+        let mut labels = LabelGenerator::new(LabelStyle::Compact);
+        let mut memory = Memory::new(scratch, vec![], Goal::Size);
+        expression.to_command(&mut labels, &mut memory, None).unwrap()
+    }
+    
+    #[test]
+    fn subtract_inputs_in_order() {
+        let difference = Subtract::new(Box::new(Input {}), Box::new(Input {}));
+        let scratch = Reference::Pointer(0);
+        assert_eq!(compile(&difference, vec![0]), vec![
+            Command::Inbox,
+            CopyTo(scratch.clone()),
+            Command::Inbox,
+            Command::Subtract(scratch.clone()),
+            CopyTo(scratch.clone()),
+            Command::Subtract(scratch.clone()),
+            Command::Subtract(scratch),
+        ]);
+        assert_eq!(compile(&difference, vec![0, 1]), vec![
+            Command::Inbox,
+            CopyTo(Reference::Pointer(0)),
+            Command::Inbox,
+            CopyTo(Reference::Pointer(1)),
+            Command::CopyFrom(Reference::Pointer(0)),
+            Command::Subtract(Reference::Pointer(1)),
+        ]);
+    }
+    
+    #[test]
+    fn subtract_after_increment() {
+        //x++ - x
+        let difference = Subtract::new(Box::new(Increment::new(tile(3))), tile(3));
+        assert_eq!(compile(&difference, vec![0]), vec![
+            Command::Increment(Reference::Pointer(3)),
+            Command::Subtract(Reference::Pointer(3)),
+        ]);
+    }
+    
+    #[test]
+    fn subtract_increment_of_pointer() {
+        //*p - p++, the tile p points to is read before p moves on
+        let pointer = Box::new(Deref::new(tile(20)));
+        let difference = Subtract::new(pointer, Box::new(Increment::new(tile(20))));
+        let scratch = Reference::Pointer(0);
+        assert_eq!(compile(&difference, vec![0]), vec![
+            Command::CopyFrom(Reference::PointerPointer(20)),
+            CopyTo(scratch.clone()),
+            Command::Increment(Reference::Pointer(20)),
+            Command::Subtract(scratch.clone()),
+            CopyTo(scratch.clone()),
+            Command::Subtract(scratch.clone()),
+            Command::Subtract(scratch),
+        ]);
+    }
+    
+    #[test]
+    fn repeated_loop_body_is_written_once() {
+        let echo = |line| Box::new(Statement::new(Box::new(Output::new(Box::new(Input {}))), Location { file: String::from("test"), line })) as Box<dyn Expression>;
+        let bump = || Box::new(Increment::new(tile(3))) as Box<dyn Expression>;
+        let simplified = |contents: Vec<Box<dyn Expression>>, goal| {
+            let mut body = Loop::new(contents);
+            body.simplify(&Memory::new(vec![], vec![], goal));
+            body.contents.len()
+        };
+        assert_eq!(simplified(vec![echo(1), echo(2), echo(3)], Goal::Size), 1);
+        assert_eq!(simplified(vec![echo(1), echo(2)], Goal::Speed), 2);
+        assert_eq!(simplified(vec![echo(1), bump(), echo(3), bump()], Goal::Size), 2);
+        assert_eq!(simplified(vec![echo(1), bump(), echo(3)], Goal::Size), 3);
+    }
+}
@@ -0,0 +1,140 @@
+//! Rewrites short runs of commands into shorter ones that do the same thing. The rules only look
+//! at commands that are next to each other, so they are run again and again until none of them
+//! changes anything. When optimising for speed, jumps to a few commands that end with a jump are
+//! replaced with a copy of them afterwards.
+
+use crate::compiler::{Command, Goal, LabelRef};
+use crate::ir::DUPLICATE_LIMIT;
+
+/// A rule looks at the start of the commands it is given. If it applies, it says how many commands
+/// it replaces and what with, which always has to be fewer commands so the rules can't go on
+/// forever.
+type Rule = fn(&[Command]) -> Option<(usize, Vec<Command>)>;
+
+const RULES: &[Rule] = &[
+    store_then_load,
+    load_then_store,
+    overwritten_load,
+    jump_to_next,
+    same_branches,
+];
+
+pub fn optimize(mut commands: Vec<Command>, goal: Goal) -> Vec<Command> {
+    while let Some(optimized) = apply_rules(&commands) {
+        commands = optimized;
+    }
+    if goal == Goal::Speed {
+        commands = copy_jump_targets(&commands);
+        while let Some(optimized) = apply_rules(&commands) {
+            commands = optimized;
+        }
+    }
+    
+    commands
+}
+
+/// Replaces each `JUMP x` with the commands at `x` if they are short, have no labels and end with
+/// a jump, which saves a step each time. The program gets longer, so this is only done when
+/// optimising for speed, and only once since the copies could be copied again.
+fn copy_jump_targets(commands: &[Command]) -> Vec<Command> {
+    commands.iter()
+        .enumerate()
+        .flat_map(|(i, command)| match command {
+            Command::Jump(target) => short_run_at(commands, target, i).unwrap_or_else(|| vec![command.clone()]),
+            command => vec![command.clone()],
+        })
+        .collect()
+}
+
+/// The commands after the label `target` up to and including the next `JUMP`, unless there are
+/// too many, they have a label in between or the jump at `from` is one of them
+fn short_run_at(commands: &[Command], target: &LabelRef, from: usize) -> Option<Vec<Command>> {
+    let label = commands.iter().position(|e| matches!(e, Command::Label(label) if label.name() == target.name()))?;
+    let start = label + following_labels(&commands[label..]).count();
+    let length = commands[start..].iter().position(|e| matches!(e, Command::Jump(_)))?;
+    let run = &commands[start..=start + length];
+    if length > DUPLICATE_LIMIT || (start..=start + length).contains(&from) || run.iter().any(|e| matches!(e, Command::Label(_))) {
+        return None;
+    }
+    Some(run.to_vec())
+}
+
+/// Goes over the commands once, gives `None` if no rule applied anywhere
+fn apply_rules(commands: &[Command]) -> Option<Vec<Command>> {
+    let mut out = Vec::with_capacity(commands.len());
+    let mut changed = false;
+    let mut i = 0;
+    while i < commands.len() {
+        match RULES.iter().find_map(|rule| rule(&commands[i..])) {
+            Some((replaced, replacement)) => {
+                out.extend(replacement);
+                i += replaced;
+                changed = true;
+            }
+            None => {
+                out.push(commands[i].clone());
+                i += 1;
+            }
+        }
+    }
+    
+    if changed { Some(out) } else { None }
+}
+
+/// `COPYTO x; COPYFROM x`, the value is still in the hand
+fn store_then_load(commands: &[Command]) -> Option<(usize, Vec<Command>)> {
+    match commands {
+        [Command::CopyTo(to), Command::CopyFrom(from), ..] if to == from => Some((2, vec![commands[0].clone()])),
+        _ => None,
+    }
+}
+
+/// `COPYFROM x; COPYTO x`, the tile already holds the value
+fn load_then_store(commands: &[Command]) -> Option<(usize, Vec<Command>)> {
+    match commands {
+        [Command::CopyFrom(from), Command::CopyTo(to), ..] if to == from => Some((2, vec![commands[0].clone()])),
+        _ => None,
+    }
+}
+
+/// `COPYFROM x` followed by something that picks up a new value without looking at the hand
+fn overwritten_load(commands: &[Command]) -> Option<(usize, Vec<Command>)> {
+    match commands {
+        [Command::CopyFrom(_), Command::CopyFrom(_), ..] |
+        [Command::CopyFrom(_), Command::Inbox, ..] => Some((1, vec![])),
+        _ => None,
+    }
+}
+
+/// A jump to one of the labels right after it goes to the same place either way
+fn jump_to_next(commands: &[Command]) -> Option<(usize, Vec<Command>)> {
+    let target = match commands.first()? {
+        Command::Jump(target) | Command::JumpIfZero(target) | Command::JumpIfNegative(target) => target,
+        _ => return None,
+    };
+    
+    if following_labels(&commands[1..]).any(|label| label.name() == target.name()) {
+        Some((1, vec![]))
+    } else {
+        None
+    }
+}
+
+/// `JUMPZ x; JUMP x`, the test doesn't change where the program goes
+fn same_branches(commands: &[Command]) -> Option<(usize, Vec<Command>)> {
+    match commands {
+        [Command::JumpIfZero(taken), Command::Jump(target), ..] |
+        [Command::JumpIfNegative(taken), Command::Jump(target), ..] if taken == target => {
+            Some((2, vec![commands[1].clone()]))
+        }
+        _ => None,
+    }
+}
+
+/// The labels at the start of `commands`, before the first command that isn't a label
+fn following_labels(commands: &[Command]) -> impl Iterator<Item = LabelRef> + '_ {
+    commands.iter().map_while(|command| match command {
+        Command::Label(label) => Some(label.reference()),
+        _ => None,
+    })
+}
\ No newline at end of file
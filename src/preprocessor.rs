@@ -1,28 +1,659 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
 use lazy_static::lazy_static;
 use regex::Regex;
+use crate::CompileError;
+use crate::lexer::{lex, LexError};
+use crate::parser2::VALUE_RANGE;
 
-pub fn parse_macros(source: String) -> String {
-    lazy_static! {
-        static ref FINDER: Regex = Regex::new("#define ([\\w]+) ([\\w*]+)").unwrap();
+/// Expands macros. `#define name value` replaces the word `name` with the rest of the line, and
+/// `#define name(a, b) value` replaces calls like `name(*1, *2)`, with the arguments put in place
+/// of the parameters. A line ending in `\` continues on the next one. Only whole identifiers are
+/// replaced, a definition applies from its own line onwards, and `#undef name` removes it again.
+/// `defines` are the macros given on the command line, which apply from the start.
+///
+/// Labels declared inside a macro get a new name in every expansion, so a macro that uses
+/// `label` and `goto` can be used more than once. A macro that ends up expanding itself is an
+/// error.
+///
+/// `#ifdef name`, `#ifndef name` and `#if expression` keep the lines up to the matching `#else` or
+/// `#endif` only if the condition holds, and the lines after `#else` only if it doesn't. The lines
+/// that are left out still have to be made of valid tokens, but their directives are ignored.
+///
+/// `#include "file"` puts the lines of another file in place of the directive. The file is looked
+/// for next to the file including it and then in `include_paths`, while `#include <file>` only
+/// looks in `include_paths`. A file containing `#pragma once` is only included the first time.
+///
+/// Comments are removed before anything else, so directives inside them are ignored. `/* */`
+/// comments can be nested. With `keep_comments`, a line that only holds a comment is replaced
+/// with `comment(n);`, which becomes an HRM `COMMENT` marker, numbered from 0.
+///
+/// The directive lines and the lines that are left out are emptied, so apart from included files
+/// the line numbers stay the same. `file` is the path of `source`, used to find includes and in
+/// error messages.
+///
+/// Next to the lines, it gives the macros that stand for a tile, like `#define count *3`, which
+/// are the variables of the program, and where each of the lines came from.
+pub fn parse_macros(source: String, file: &Path, include_paths: &[PathBuf], defines: &[(String, String)],
+                    keep_comments: bool) -> Result<(String, Vec<Variable>, Vec<Location>), CompileError> {
+    let command_line = Location { file: String::from("<command line>"), line: 0 };
+    let mut preprocessor = Preprocessor {
+        expander: Expander {
+            macros: defines.iter()
+                .map(|e| (e.0.clone(), Macro { parameters: None, body: e.1.clone(), location: command_line.clone() }))
+                .collect(),
+            active: Vec::new(),
+            expansions: 0,
+            location: command_line,
+        },
+        include_paths,
+        included_once: HashSet::new(),
+        including: Vec::new(),
+        comments: if keep_comments { Some(0) } else { None },
+        variables: Vec::new(),
+        locations: Vec::new(),
+    };
+    
+    let mut lines = Vec::new();
+    preprocessor.process(&source, file, &mut lines)?;
+    
+    Ok((lines.join("\n"), preprocessor.variables, preprocessor.locations))
+}
+
+/// A macro that stands for a tile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub tile: u8,
+    pub location: Location,
+}
+
+/// A line in one of the source files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+struct Preprocessor<'a> {
+    expander: Expander,
+    include_paths: &'a [PathBuf],
+    /// The files that contained `#pragma once`
+    included_once: HashSet<PathBuf>,
+    /// The files that are being processed right now, outermost first
+    including: Vec<PathBuf>,
+    /// The number of the next comment marker, if comments are kept
+    comments: Option<u16>,
+    variables: Vec<Variable>,
+    /// Where each of the lines given back so far came from
+    locations: Vec<Location>,
+}
+
+impl Preprocessor<'_> {
+    /// Adds the lines of `source`, which was read from `file`, to `lines`
+    fn process(&mut self, source: &str, file: &Path, lines: &mut Vec<String>) -> Result<(), CompileError> {
+        lazy_static! {
+            static ref DEFINE: Regex = Regex::new(r"^\s*#define\s+([A-Za-z_]\w*)(\(([^)]*)\))?\s*(.*)$").unwrap();
+            static ref UNDEF: Regex = Regex::new(r"^\s*#undef\s+([A-Za-z_]\w*)\s*$").unwrap();
+            static ref CONDITIONAL: Regex = Regex::new(r"^\s*#(ifdef|ifndef|if|else|endif)\b\s*(.*)$").unwrap();
+            static ref INCLUDE: Regex = Regex::new(r#"^\s*#include\s*("([^"]*)"|<([^>]*)>)\s*$"#).unwrap();
+            static ref PRAGMA_ONCE: Regex = Regex::new(r"^\s*#pragma\s+once\s*$").unwrap();
+            static ref TILE: Regex = Regex::new(r"^\*\s*(\d+)$").unwrap();
+        }
+        
+        let identity = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+        self.including.push(identity.clone());
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let source = self.strip_comments(source, file)?;
+        
+        for (number, line, continuations) in join_continued_lines(&source) {
+            let location = Location { file: file.display().to_string(), line: number };
+            self.expander.location = location.clone();
+            let including = conditionals.iter().all(|e| e.including);
+            let line = line.as_str();
+            
+            if let Some(captures) = CONDITIONAL.captures(line) {
+                let argument = captures[2].trim();
+                match &captures[1] {
+                    "else" => {
+                        let conditional = conditionals.last_mut()
+                            .filter(|e| !e.seen_else)
+                            .ok_or_else(|| directive_error("`#else` without an `#if`", &location))?;
+                        conditional.seen_else = true;
+                        conditional.including = !conditional.taken;
+                        conditional.taken = true;
+                    }
+                    "endif" => {
+                        conditionals.pop().ok_or_else(|| directive_error("`#endif` without an `#if`", &location))?;
+                    }
+                    directive => {
+                        //the conditions inside a branch that is left out aren't looked at
+                        let holds = including && match directive {
+                            "ifdef" => self.expander.macros.contains_key(identifier(argument, &location)?),
+                            "ifndef" => !self.expander.macros.contains_key(identifier(argument, &location)?),
+                            _ => self.expander.evaluate(argument)? != 0,
+                        };
+                        conditionals.push(Conditional {
+                            location,
+                            including: holds,
+                            taken: holds || !including,
+                            seen_else: false,
+                        });
+                    }
+                }
+                lines.push(String::new());
+            } else if !including {
+                let code = line.trim();
+                if !code.starts_with('#') && !code.is_empty() {
+                    lex(code).map_err(|LexError::InvalidTokenError { text, .. }| {
+                        directive_error(&format!("invalid code in a left out branch: `{}`", text), &location)
+                    })?;
+                }
+                lines.push(String::new());
+            } else if let Some(captures) = INCLUDE.captures(line) {
+                let (name, quoted) = match captures.get(2) {
+                    Some(name) => (name.as_str(), true),
+                    None => (&captures[3], false),
+                };
+                self.include(name, quoted, file, &location, lines)?;
+            } else if PRAGMA_ONCE.is_match(line) {
+                self.included_once.insert(identity.clone());
+                lines.push(String::new());
+            } else if let Some(captures) = DEFINE.captures(line) {
+                let name = captures[1].to_string();
+                let parameters = match captures.get(3) {
+                    Some(list) => Some(parse_parameters(list.as_str())
+                        .ok_or_else(|| self.expander.error("invalid parameter list", &name, &location))?),
+                    None => None,
+                };
+                let definition = Macro {
+                    parameters,
+                    body: captures[4].trim().to_string(),
+                    location: location.clone(),
+                };
+                if let Some(old) = self.expander.macros.get(&name) {
+                    if old.parameters != definition.parameters || old.body != definition.body {
+                        eprintln!("warning: {}: macro `{}` redefined, it was defined at {}", location, name, old.location);
+                    }
+                }
+                if let Some(tile) = TILE.captures(&definition.body).filter(|_| definition.parameters.is_none()) {
+                    if let Ok(tile) = tile[1].parse() {
+                        self.variables.push(Variable { name: name.clone(), tile, location: location.clone() });
+                    }
+                }
+                self.expander.macros.insert(name, definition);
+                lines.push(String::new());
+            } else if let Some(captures) = UNDEF.captures(line) {
+                self.expander.macros.remove(&captures[1]);
+                lines.push(String::new());
+            } else {
+                lines.push(self.expander.expand(line)?);
+            }
+            lines.extend(std::iter::repeat_n(String::new(), continuations));
+            //an included file already added the locations of its own lines
+            let known = self.locations.len();
+            self.locations.extend((known..lines.len()).map(|i| Location {
+                file: file.display().to_string(),
+                line: number + i - known,
+            }));
+        }
+        
+        if let Some(conditional) = conditionals.pop() {
+            return Err(directive_error("`#if` without an `#endif`", &conditional.location));
+        }
+        self.including.pop();
+        
+        Ok(())
     }
     
-    let matches: Vec<(String, String, String)> = FINDER.captures_iter(&source)
-        .map(|e| (e.get(1).unwrap().as_str().to_string(),
-                  e.get(2).unwrap().as_str().to_string(),
-                  e.get(0).unwrap().as_str().to_string()))
-        .collect();
+    /// Removes the comments but not the line breaks inside of them
+    fn strip_comments(&mut self, source: &str, file: &Path) -> Result<String, CompileError> {
+        let mut out = String::new();
+        let mut chars = source.chars().peekable();
+        let mut line = 1;
+        //the lines that comments start on, and the line where the open block comment started
+        let mut comment_lines = Vec::new();
+        let mut open = Vec::new();
+        
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('\n', _) => {
+                    line += 1;
+                    out.push(c);
+                }
+                ('/', Some('*')) => {
+                    chars.next();
+                    if open.is_empty() {
+                        comment_lines.push(line);
+                    }
+                    open.push(line);
+                }
+                ('*', Some('/')) if !open.is_empty() => {
+                    chars.next();
+                    open.pop();
+                    if open.is_empty() {
+                        out.push(' ');
+                    }
+                }
+                ('/', Some('/')) if open.is_empty() => {
+                    comment_lines.push(line);
+                    while chars.next_if(|e| *e != '\n').is_some() {}
+                }
+                _ if open.is_empty() => out.push(c),
+                _ => {}
+            }
+        }
+        
+        if let Some(line) = open.first() {
+            let location = Location { file: file.display().to_string(), line: *line };
+            return Err(directive_error("`/*` without a `*/`", &location));
+        }
+        
+        let counter = match &mut self.comments {
+            Some(counter) => counter,
+            None => return Ok(out),
+        };
+        let mut lines = Vec::new();
+        for (i, e) in out.lines().enumerate() {
+            if e.trim().is_empty() && comment_lines.contains(&(i + 1)) {
+                let number = u8::try_from(*counter).map_err(|_| {
+                    let location = Location { file: file.display().to_string(), line: i + 1 };
+                    directive_error("there are more than 256 comments to keep", &location)
+                })?;
+                *counter += 1;
+                lines.push(format!("comment({});", number));
+            } else {
+                lines.push(e.to_string());
+            }
+        }
+        
+        Ok(lines.join("\n"))
+    }
     
-    let mut res = source;
+    fn include(&mut self, name: &str, quoted: bool, from: &Path, location: &Location, lines: &mut Vec<String>) -> Result<(), CompileError> {
+        let next_to_file = from.parent().map(|e| e.join(name)).filter(|_| quoted);
+        let path = next_to_file.into_iter()
+            .chain(self.include_paths.iter().map(|e| e.join(name)))
+            .find(|e| e.is_file())
+            .ok_or_else(|| directive_error(&format!("can't find the included file `{}`", name), location))?;
+        let identity = path.canonicalize().unwrap_or_else(|_| path.clone());
+        
+        if self.included_once.contains(&identity) {
+            lines.push(String::new());
+            return Ok(());
+        }
+        //a file can include itself once through include guards, if it happens again the guards
+        //aren't stopping it
+        if self.including.iter().filter(|e| **e == identity).count() >= 2 {
+            let start = self.including.iter().rposition(|e| *e == identity).unwrap();
+            let cycle: Vec<String> = self.including[start..].iter()
+                .chain(std::iter::once(&identity))
+                .map(|e| e.display().to_string())
+                .collect();
+            return Err(directive_error(&format!("files include each other: {}", cycle.join(" -> ")), location));
+        }
+        
+        let source = std::fs::read_to_string(&path)
+            .map_err(|e| directive_error(&format!("can't read the included file `{}`: {}", path.display(), e), location))?;
+        self.process(&source, &path, lines)
+    }
+}
+
+/// An `#if`, `#ifdef` or `#ifndef` that hasn't reached its `#endif` yet
+struct Conditional {
+    location: Location,
+    /// Whether the lines in the current branch are kept
+    including: bool,
+    /// Whether a branch has been kept already, or none of them will be
+    taken: bool,
+    seen_else: bool,
+}
+
+fn identifier<'a>(text: &'a str, location: &Location) -> Result<&'a str, CompileError> {
+    if is_identifier(text) && text.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        Ok(text)
+    } else {
+        Err(directive_error(&format!("expected a macro name but found `{}`", text), location))
+    }
+}
+
+fn directive_error(message: &str, location: &Location) -> CompileError {
+    CompileError::DirectiveError { message: message.to_string(), location: location.clone() }
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    parameters: Option<Vec<String>>,
+    body: String,
+    /// Where the macro was defined
+    location: Location,
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    /// The macros that are being expanded right now, innermost last
+    active: Vec<String>,
+    /// Counts the expansions so far, used to give labels inside macros unique names
+    expansions: usize,
+    /// The line that is being expanded
+    location: Location,
+}
+
+impl Expander {
+    fn expand(&mut self, text: &str) -> Result<String, CompileError> {
+        let pieces = pieces(text);
+        let mut out = String::new();
+        //directive names such as `#add_square` are never expanded
+        let mut after_hash = false;
+        let mut i = 0;
+        
+        while i < pieces.len() {
+            let piece = pieces[i];
+            i += 1;
+            
+            let definition = match self.macros.get(piece) {
+                Some(definition) if !after_hash && is_identifier(piece) => definition.clone(),
+                _ => {
+                    after_hash = piece == "#";
+                    out.push_str(piece);
+                    continue;
+                }
+            };
+            
+            let arguments = match &definition.parameters {
+                Some(parameters) => {
+                    let open = (i..pieces.len()).find(|&j| !pieces[j].trim().is_empty());
+                    if open.map(|j| pieces[j]) != Some("(") {
+                        //a function-like macro without arguments is just a word
+                        out.push_str(piece);
+                        continue;
+                    }
+                    
+                    let (arguments, end) = split_call(&pieces, open.unwrap() + 1)
+                        .ok_or_else(|| self.error("call is missing a `)`", piece, &definition.location))?;
+                    i = end;
+                    if arguments.len() != parameters.len() {
+                        return Err(self.error(&format!("expected {} arguments but got {}", parameters.len(), arguments.len()),
+                                              piece, &definition.location));
+                    }
+                    
+                    let mut expanded = Vec::new();
+                    for argument in arguments {
+                        expanded.push(self.expand(argument.trim())?);
+                    }
+                    expanded
+                }
+                None => vec![],
+            };
+            
+            if self.active.iter().any(|e| e == piece) {
+                return Err(self.error("macro expands to itself", piece, &definition.location));
+            }
+            
+            let body = self.substitute(piece, &definition, &arguments);
+            self.active.push(piece.to_string());
+            let expanded = self.expand(&body);
+            self.active.pop();
+            out.push_str(&expanded?);
+        }
+        
+        Ok(out)
+    }
     
-    for m in &matches {
-        res = res.replace(&m.2, "");
+    /// Puts the arguments in place of the parameters in one pass, so an argument is never mistaken
+    /// for a parameter, and renames the labels declared in the body.
+    fn substitute(&mut self, name: &str, definition: &Macro, arguments: &[String]) -> String {
+        self.expansions += 1;
+        let body = pieces(&definition.body);
+        let parameters = definition.parameters.as_deref().unwrap_or(&[]);
+        
+        let mut labels = Vec::new();
+        let mut words = body.iter().filter(|e| !e.trim().is_empty());
+        while let Some(word) = words.next() {
+            if *word == "label" {
+                if let Some(label) = words.next().filter(|e| is_identifier(e)) {
+                    labels.push(*label);
+                }
+            }
+        }
+        
+        body.iter()
+            .map(|piece| match parameters.iter().position(|e| e == piece) {
+                Some(n) => arguments[n].clone(),
+                None if labels.contains(piece) => format!("{}_{}_{}", piece, name, self.expansions),
+                None => piece.to_string(),
+            })
+            .collect()
     }
     
-    for m in &matches {
-        res = res.replace(&m.0, &m.1);
+    /// Works out the value of an `#if` condition. Macros are expanded first, except in
+    /// `defined(name)`, and names that are left afterwards count as 0.
+    fn evaluate(&mut self, condition: &str) -> Result<i64, CompileError> {
+        lazy_static! {
+            static ref DEFINED: Regex = Regex::new(r"\bdefined\s*(\(\s*([A-Za-z_]\w*)\s*\)|([A-Za-z_]\w*))").unwrap();
+        }
+        
+        let condition = DEFINED.replace_all(condition, |captures: &regex::Captures| {
+            let name = captures.get(2).or_else(|| captures.get(3)).unwrap().as_str();
+            if self.macros.contains_key(name) { "1" } else { "0" }
+        }).to_string();
+        let expanded = self.expand(&condition)?;
+        
+        let tokens: Vec<&str> = pieces(&expanded).into_iter().filter(|e| !e.trim().is_empty()).collect();
+        let mut evaluator = Evaluator { tokens, position: 0 };
+        match evaluator.or() {
+            Some(value) if evaluator.position == evaluator.tokens.len() => Ok(value),
+            _ => Err(directive_error(&format!("invalid condition `{}`", condition), &self.location)),
+        }
     }
     
-    res
+    fn error(&self, message: &str, name: &str, definition: &Location) -> CompileError {
+        CompileError::MacroError {
+            message: format!("`{}`: {}", name, message),
+            definition: definition.clone(),
+            call: self.location.clone(),
+        }
+    }
+}
+
+/// Evaluates the tokens of an `#if` condition, with the operators `|| && == != < > <= >= + - !`
+/// and parentheses, from the loosest to the tightest binding.
+struct Evaluator<'a> {
+    tokens: Vec<&'a str>,
+    position: usize,
+}
+
+impl Evaluator<'_> {
+    fn or(&mut self) -> Option<i64> {
+        let mut value = self.and()?;
+        while self.operator("||") {
+            let right = self.and()?;
+            value = (value != 0 || right != 0) as i64;
+        }
+        Some(value)
+    }
+    
+    fn and(&mut self) -> Option<i64> {
+        let mut value = self.comparison()?;
+        while self.operator("&&") {
+            let right = self.comparison()?;
+            value = (value != 0 && right != 0) as i64;
+        }
+        Some(value)
+    }
+    
+    fn comparison(&mut self) -> Option<i64> {
+        let left = self.sum()?;
+        for operator in &["==", "!=", "<=", ">=", "<", ">"] {
+            if self.operator(operator) {
+                let right = self.sum()?;
+                let result = match *operator {
+                    "==" => left == right,
+                    "!=" => left != right,
+                    "<=" => left <= right,
+                    ">=" => left >= right,
+                    "<" => left < right,
+                    _ => left > right,
+                };
+                return Some(result as i64);
+            }
+        }
+        Some(left)
+    }
+    
+    fn sum(&mut self) -> Option<i64> {
+        let mut value = self.unary()?;
+        loop {
+            if self.operator("+") {
+                value += self.unary()?;
+            } else if self.operator("-") {
+                value -= self.unary()?;
+            } else {
+                return Some(value);
+            }
+        }
+    }
+    
+    fn unary(&mut self) -> Option<i64> {
+        if self.operator("!") {
+            Some((self.unary()? == 0) as i64)
+        } else if self.operator("-") {
+            Some(-self.unary()?)
+        } else if self.operator("(") {
+            let value = self.or()?;
+            if self.operator(")") { Some(value) } else { None }
+        } else {
+            let token = self.tokens.get(self.position)?;
+            self.position += 1;
+            match token.parse::<i64>() {
+                Ok(value) => Some(value),
+                Err(_) if is_identifier(token) => Some(0),
+                Err(_) => None,
+            }
+        }
+    }
+    
+    /// Moves past `operator` if it comes next. The tokens are single characters, so operators
+    /// like `<=` are matched over several of them.
+    fn operator(&mut self, operator: &str) -> bool {
+        let length = operator.len();
+        let next = self.tokens.get(self.position..self.position + length).map(|e| e.concat());
+        //`<` shouldn't match the start of `<=`, and `!` not the start of `!=`
+        let longer = matches!(operator, "<" | ">" | "!") && self.tokens.get(self.position + 1) == Some(&"=");
+        
+        if next.as_deref() == Some(operator) && !longer {
+            self.position += length;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Joins lines ending with `\` to the next line. Gives the line number each joined line started
+/// on and how many lines were joined onto it.
+fn join_continued_lines(source: &str) -> Vec<(usize, String, usize)> {
+    let mut out = Vec::new();
+    let mut current: Option<(usize, String, usize)> = None;
+    
+    for (number, line) in source.lines().enumerate() {
+        let (start, mut text, continuations) = match current.take() {
+            Some((start, text, continuations)) => (start, text, continuations + 1),
+            None => (number + 1, String::new(), 0),
+        };
+        
+        match line.trim_end().strip_suffix('\\') {
+            Some(code) => {
+                text.push_str(code);
+                text.push(' ');
+                current = Some((start, text, continuations));
+            }
+            None => {
+                text.push_str(line);
+                out.push((start, text, continuations));
+            }
+        }
+    }
+    out.extend(current);
+    
+    out
+}
+
+fn parse_parameters(list: &str) -> Option<Vec<String>> {
+    if list.trim().is_empty() {
+        return Some(vec![]);
+    }
+    
+    let parameters: Vec<String> = list.split(',').map(|e| e.trim().to_string()).collect();
+    let unique = parameters.iter().enumerate().all(|(i, e)| !parameters[..i].contains(e));
+    if unique && parameters.iter().all(|e| is_identifier(e)) {
+        Some(parameters)
+    } else {
+        None
+    }
+}
+
+/// Splits the arguments of a call, starting after its `(`. Gives the arguments and the index
+/// after the closing `)`.
+fn split_call(pieces: &[&str], start: usize) -> Option<(Vec<String>, usize)> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    
+    for (i, piece) in pieces.iter().enumerate().skip(start) {
+        match *piece {
+            ")" if depth == 0 => {
+                if !(arguments.is_empty() && current.trim().is_empty()) {
+                    arguments.push(current);
+                }
+                return Some((arguments, i + 1));
+            }
+            "," if depth == 0 => {
+                arguments.push(std::mem::take(&mut current));
+                continue;
+            }
+            "(" => depth += 1,
+            ")" => depth -= 1,
+            _ => {}
+        }
+        current.push_str(piece);
+    }
+    
+    None
+}
+
+/// Splits text into words and single characters
+fn pieces(text: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut word_start = None;
+    
+    for (i, c) in text.char_indices() {
+        if c.is_alphanumeric() || c == '_' {
+            word_start.get_or_insert(i);
+            continue;
+        }
+        
+        if let Some(start) = word_start.take() {
+            out.push(&text[start..i]);
+        }
+        out.push(&text[i..i + c.len_utf8()]);
+    }
+    if let Some(start) = word_start {
+        out.push(&text[start..]);
+    }
+    
+    out
+}
+
+fn is_identifier(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_alphabetic() || c == '_')
 }
 
 pub fn find_add_square(source: String) -> (String, u8) {
@@ -47,32 +678,71 @@ pub fn find_add_square(source: String) -> (String, u8) {
     (source.replace(&remove, ""), num)
 }
 
-pub fn trim(mut source: String) -> String {
+/// Finds the scratch tiles declared with `#scratch 20..23`, which doesn't include tile 23, or
+/// `#scratch 20..=23`, which does. `locations` says where each line of `source` came from.
+pub fn find_scratch(source: String, locations: &[Location]) -> Result<(String, Vec<u8>), CompileError> {
     lazy_static! {
-        static ref COMMENT_FINDER: Regex = {
-            Regex::new("//.*").unwrap()
-        };
-        static ref WHITESPACE_TRIMMER: Regex = {
-            Regex::new("(^[\\s]+|[\\s]+$)").unwrap()
+        static ref FINDER: Regex = Regex::new("#scratch ([\\d]+)\\.\\.(=?)([\\d]+)").unwrap();
+    }
+    
+    let mut tiles = Vec::new();
+    let mut found = Vec::new();
+    for e in FINDER.captures_iter(&source) {
+        let directive = e.get(0).unwrap();
+        let line = source[..directive.start()].matches('\n').count();
+        let tile = |i: usize| {
+            let number = e.get(i).unwrap().as_str();
+            number.parse::<u8>().map_err(|_| {
+                directive_error(&format!("`{}` isn't a tile number", number), &locations[line])
+            })
         };
+        let (start, end) = (tile(1)?, tile(3)?);
+        if e.get(2).unwrap().as_str().is_empty() {
+            tiles.extend(start..end);
+        } else {
+            tiles.extend(start..=end);
+        }
+        found.push(directive.as_str().to_string());
     }
-
-    let comments: Vec<String> = COMMENT_FINDER.find_iter(&source)
-        .map(|e| e.as_str().to_string())
-        .collect();
-    for comment in comments {
-        source = source.replace(&comment, "");
+    
+    let mut res = source;
+    
+    for m in &found {
+        res = res.replace(m, "");
     }
     
-    let mut whitespaces: Vec<(usize, usize)> = WHITESPACE_TRIMMER.find_iter(&source)
-        .map(|e| (e.start(), e.end()))
-        .collect();
-    whitespaces.sort();
-    whitespaces.reverse();
+    Ok((res, tiles))
+}
 
-    for whitespace in whitespaces {
-        source.replace_range(whitespace.0..whitespace.1, "");
+/// Finds the tiles declared with `#constant value *tile` to always hold `value`. `locations` says
+/// where each line of `source` came from.
+pub fn find_constants(source: String, locations: &[Location]) -> Result<(String, Vec<(i16, u8)>), CompileError> {
+    lazy_static! {
+        static ref FINDER: Regex = Regex::new("#constant (-?[\\d]+) \\*([\\d]+)").unwrap();
     }
-
-    source
+    
+    let mut constants = Vec::new();
+    let mut found = Vec::new();
+    for e in FINDER.captures_iter(&source) {
+        let directive = e.get(0).unwrap();
+        let location = &locations[source[..directive.start()].matches('\n').count()];
+        let value = e.get(1).unwrap().as_str();
+        let value = value.parse::<i16>().ok().filter(|e| VALUE_RANGE.contains(e)).ok_or_else(|| {
+            directive_error(&format!("`{}` isn't a value from -999 to 999", value), location)
+        })?;
+        let tile = e.get(2).unwrap().as_str();
+        let tile = tile.parse::<u8>().map_err(|_| {
+            directive_error(&format!("`{}` isn't a tile number", tile), location)
+        })?;
+        constants.push((value, tile));
+        found.push(directive.as_str().to_string());
+    }
+    
+    let mut res = source;
+    
+    for m in &found {
+        res = res.replace(m, "");
+    }
+    
+    Ok((res, constants))
 }
\ No newline at end of file
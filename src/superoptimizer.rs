@@ -0,0 +1,307 @@
+//! Looks for the shortest run of commands that does the same thing as a short run in the program,
+//! by trying every run of commands up to a given length. Two runs count as doing the same thing
+//! when the interpreter gives the same hand, floor and inbox and outbox on a few hundred starting
+//! states, both chosen at random and at the edges like 999 and empty tiles. That is testing and
+//! not a proof, which is why this only runs when it is asked for. What was found is cached in a
+//! file, since the search takes a while.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use crate::compiler::{Command, Reference};
+use crate::interpreter::Worker;
+use crate::ir::Cfg;
+
+/// The longest run of commands that is replaced as a whole
+const WINDOW: usize = 8;
+/// Runs that use more tiles than this have too many candidates to try
+const MAX_TILES: usize = 4;
+/// How many starting states the candidates are tested on
+const TESTS: usize = 300;
+/// How many of the tests are run on the start of a candidate to stop trying the ones starting
+/// the same way early
+const PRUNE_TESTS: usize = 4;
+/// Numbers that are likely to show where two runs differ
+const EDGES: &[i16] = &[0, 1, -1, 2, -2, 999, -999, 998, -998];
+/// The first line of the cache file, which is ignored when it changes
+const CACHE_VERSION: &str = "# hrm superoptimizer cache 1";
+
+/// Replaces the runs of commands in each block that have a shorter equivalent with up to
+/// `longest` commands.
+pub fn optimize(cfg: &mut Cfg, longest: usize) {
+    let mut cache = Cache::load(cache_path());
+    for block in &mut cfg.blocks {
+        let mut start = 0;
+        while start < block.commands.len() {
+            let end = block.commands[start..].iter()
+                .position(|e| !searchable(e))
+                .map_or(block.commands.len(), |e| start + e);
+            let found = (2..=WINDOW.min(end - start)).rev().find_map(|length| {
+                let shorter = cache.shortest(&block.commands[start..start + length], longest)?;
+                Some((length, shorter))
+            });
+            match found {
+                //the run that took its place might be part of a longer run with a shorter equivalent
+                Some((length, shorter)) => drop(block.commands.splice(start..start + length, shorter)),
+                None => start += 1,
+            }
+        }
+    }
+    
+    if let Err(e) = cache.save() {
+        eprintln!("warning: couldn't save the superoptimizer cache: {}", e);
+    }
+}
+
+/// Whether the search can look at `command`. Pointers are left out, because numbering the tiles
+/// again would change what they point to.
+fn searchable(command: &Command) -> bool {
+    match command {
+        Command::Inbox | Command::Outbox => true,
+        Command::CopyFrom(tile) | Command::CopyTo(tile) | Command::Add(tile) | Command::Subtract(tile) |
+        Command::Increment(tile) | Command::Decrement(tile) => matches!(tile, Reference::Pointer(_)),
+        _ => false,
+    }
+}
+
+/// The shortest run of up to `longest` commands that does the same as `run`, if it is shorter
+/// than `run`. The tiles of `run` are numbered from 0.
+fn search(run: &[Command], tiles: usize, longest: usize) -> Option<Vec<Command>> {
+    if tiles > MAX_TILES {
+        return None;
+    }
+    let tests = tests(run, tiles);
+    if tests.is_empty() {
+        return None;
+    }
+    
+    let alphabet = alphabet(tiles);
+    (0..run.len().min(longest + 1)).find_map(|length| {
+        let workers = tests.iter().take(PRUNE_TESTS).map(|(start, _)| start.clone()).collect();
+        extend(&mut Vec::with_capacity(length), length, workers, &alphabet, &tests)
+    })
+}
+
+/// Tries every way of making `candidate` `length` commands long. `workers` are the first tests
+/// after running `candidate` so far.
+fn extend(candidate: &mut Vec<Command>, length: usize, workers: Vec<Worker>, alphabet: &[Command],
+          tests: &[(Worker, Worker)]) -> Option<Vec<Command>> {
+    if candidate.len() == length {
+        let passes = tests.iter().all(|(start, end)| {
+            let mut worker = start.clone();
+            worker.run(candidate).is_ok() && same_outcome(&worker, end)
+        });
+        return if passes { Some(candidate.clone()) } else { None };
+    }
+    
+    for command in alphabet {
+        //the tests that fail or stop or go to the outbox differently already fail at the end too
+        let next = workers.iter().zip(tests).map(|(worker, (_, end))| {
+            let mut worker = worker.clone();
+            worker.step(command).ok()?;
+            Some(worker).filter(|e| end.events.starts_with(&e.events) && (!e.stopped || end.stopped))
+        }).collect::<Option<Vec<Worker>>>();
+        if let Some(next) = next {
+            candidate.push(command.clone());
+            if let Some(found) = extend(candidate, length, next, alphabet, tests) {
+                return Some(found);
+            }
+            candidate.pop();
+        }
+    }
+    
+    None
+}
+
+/// Whether two workers ended the same way. After the inbox ran out, only what was sent to the
+/// outbox matters.
+fn same_outcome(worker: &Worker, expected: &Worker) -> bool {
+    worker.stopped == expected.stopped && worker.events == expected.events &&
+        (worker.stopped || (worker.hand == expected.hand && worker.floor == expected.floor))
+}
+
+/// Every command that a candidate can be made of
+fn alphabet(tiles: usize) -> Vec<Command> {
+    let commands: [fn(Reference) -> Command; 6] = [Command::CopyFrom, Command::CopyTo, Command::Add,
+        Command::Subtract, Command::Increment, Command::Decrement];
+    let mut alphabet = vec![Command::Inbox, Command::Outbox];
+    for tile in 0..tiles as u8 {
+        alphabet.extend(commands.iter().map(|command| command(Reference::Pointer(tile))));
+    }
+    
+    alphabet
+}
+
+/// Starting states and how `run` leaves them, for the states that `run` doesn't fail on. They are
+/// the same every time, so the results can be cached.
+fn tests(run: &[Command], tiles: usize) -> Vec<(Worker, Worker)> {
+    let inputs = run.iter().filter(|e| **e == Command::Inbox).count();
+    let mut random = Random(0x2545_f491_4f6c_dd1d);
+    (0..TESTS).filter_map(|i| {
+        //the first tests have everything filled in, so they are useful for cutting the search short
+        let full = i < PRUNE_TESTS * 2;
+        let hand = random.maybe_value(full);
+        let floor = (0..tiles).map(|_| random.maybe_value(full)).collect();
+        let inbox_length = if full || random.below(8) != 0 { inputs } else { random.below(inputs as u64 + 1) as usize };
+        let inbox = (0..inbox_length).map(|_| random.value()).collect();
+        let start = Worker::new(hand, floor, inbox);
+        let mut end = start.clone();
+        end.run(run).ok()?;
+        Some((start, end))
+    }).collect()
+}
+
+/// A xorshift generator, so the tests don't depend on anything outside of the program
+struct Random(u64);
+
+impl Random {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+    
+    /// A number that is often at an edge or small, so tiles often hold the same number
+    fn value(&mut self) -> i16 {
+        match self.below(3) {
+            0 => EDGES[self.below(EDGES.len() as u64) as usize],
+            1 => self.below(7) as i16 - 3,
+            _ => self.below(1999) as i16 - 999,
+        }
+    }
+    
+    /// A value that is sometimes missing, unless `full`
+    fn maybe_value(&mut self, full: bool) -> Option<i16> {
+        if !full && self.below(8) == 0 { None } else { Some(self.value()) }
+    }
+}
+
+/// Numbers the tiles of `run` from 0 in the order they are used, so runs that only differ in
+/// which tiles they use are searched once. Also gives the tile that each number stands for.
+fn normalize(run: &[Command]) -> (Vec<Command>, Vec<u8>) {
+    let mut tiles = Vec::new();
+    let run = run.iter().map(|command| map_tile(command, |tile| {
+        let index = tiles.iter().position(|e| *e == tile).unwrap_or_else(|| {
+            tiles.push(tile);
+            tiles.len() - 1
+        });
+        index as u8
+    })).collect();
+    
+    (run, tiles)
+}
+
+fn map_tile(command: &Command, mut map: impl FnMut(u8) -> u8) -> Command {
+    let mut tile = |reference: &Reference| match reference {
+        Reference::Pointer(tile) => Reference::Pointer(map(*tile)),
+        reference => reference.clone(),
+    };
+    match command {
+        Command::CopyFrom(reference) => Command::CopyFrom(tile(reference)),
+        Command::CopyTo(reference) => Command::CopyTo(tile(reference)),
+        Command::Add(reference) => Command::Add(tile(reference)),
+        Command::Subtract(reference) => Command::Subtract(tile(reference)),
+        Command::Increment(reference) => Command::Increment(tile(reference)),
+        Command::Decrement(reference) => Command::Decrement(tile(reference)),
+        command => command.clone(),
+    }
+}
+
+/// The file the results are kept in, in the user's cache folder
+fn cache_path() -> PathBuf {
+    let folder = std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    folder.join("hrm-transpiler").join("superoptimizer.txt")
+}
+
+/// The results of earlier searches. Each line of the file is the longest run that was tried, a
+/// normalised run, and the shorter run that was found or `none`, split by tabs.
+struct Cache {
+    path: PathBuf,
+    entries: HashMap<(usize, String), Option<String>>,
+    changed: bool,
+}
+
+impl Cache {
+    fn load(path: PathBuf) -> Self {
+        let mut entries = HashMap::new();
+        let file = fs::read_to_string(&path).unwrap_or_default();
+        let mut lines = file.lines();
+        if lines.next() == Some(CACHE_VERSION) {
+            for line in lines {
+                let parts: Vec<&str> = line.split('\t').collect();
+                if let [longest, run, shorter] = parts[..] {
+                    if let Ok(longest) = longest.parse() {
+                        let shorter = Some(shorter.to_string()).filter(|e| e != "none");
+                        entries.insert((longest, run.to_string()), shorter);
+                    }
+                }
+            }
+        }
+        
+        Cache { path, entries, changed: false }
+    }
+    
+    /// A shorter run that does the same as `run`, searching for one if it isn't cached
+    fn shortest(&mut self, run: &[Command], longest: usize) -> Option<Vec<Command>> {
+        let (normalized, tiles) = normalize(run);
+        let key = (longest, to_text(&normalized));
+        let shorter = match self.entries.get(&key) {
+            Some(shorter) => shorter.as_deref().and_then(from_text)?,
+            None => {
+                let shorter = search(&normalized, tiles.len(), longest);
+                self.entries.insert(key, shorter.as_deref().map(to_text));
+                self.changed = true;
+                shorter?
+            }
+        };
+        
+        Some(shorter.iter().map(|command| map_tile(command, |tile| tiles[tile as usize])).collect())
+    }
+    
+    fn save(&self) -> std::io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        if let Some(folder) = self.path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+        
+        let mut lines: Vec<String> = self.entries.iter()
+            .map(|((longest, run), shorter)| format!("{}\t{}\t{}", longest, run, shorter.as_deref().unwrap_or("none")))
+            .collect();
+        lines.sort();
+        lines.insert(0, CACHE_VERSION.to_string());
+        fs::write(&self.path, lines.join("\n"))
+    }
+}
+
+/// Writes commands on one line, like `COPYFROM 0; ADD 1`
+fn to_text(commands: &[Command]) -> String {
+    commands.iter()
+        .map(|command| command.to_string().split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Reads commands written by `to_text`, `None` if the text is broken
+fn from_text(text: &str) -> Option<Vec<Command>> {
+    text.split("; ").filter(|e| !e.is_empty()).map(|command| {
+        let mut parts = command.split(' ');
+        let name = parts.next()?;
+        let tile = parts.next().and_then(|e| e.parse().ok()).map(Reference::Pointer);
+        Some(match (name, tile) {
+            ("INBOX", None) => Command::Inbox,
+            ("OUTBOX", None) => Command::Outbox,
+            ("COPYFROM", Some(tile)) => Command::CopyFrom(tile),
+            ("COPYTO", Some(tile)) => Command::CopyTo(tile),
+            ("ADD", Some(tile)) => Command::Add(tile),
+            ("SUB", Some(tile)) => Command::Subtract(tile),
+            ("BUMPUP", Some(tile)) => Command::Increment(tile),
+            ("BUMPDN", Some(tile)) => Command::Decrement(tile),
+            _ => return None,
+        })
+    }).collect()
+}
\ No newline at end of file